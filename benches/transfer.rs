@@ -0,0 +1,39 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::fs;
+use std::hint::black_box;
+use std::time::Duration;
+use tcp_messenger_rs::bench::Loopback;
+
+///Size of each file sent
+const FILE_SIZE: usize = 32 * 1024 * 1024;
+
+///Throughput of `send_file` and the receive path over loopback,
+///for data zstd can't shrink and for data it can
+fn transfer(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("tcp_messenger_bench_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let random: Vec<u8> = (0..FILE_SIZE).map(|_| fastrand::u8(..)).collect();
+    let text: Vec<u8> = (0..FILE_SIZE / 16)
+        .flat_map(|i| format!("{:>15}\n", i % 1000).into_bytes())
+        .collect();
+    let files = [("random", random), ("text", text)];
+    for (name, data) in &files {
+        fs::write(dir.join(name), data).unwrap();
+    }
+
+    let loopback = Loopback::new(&dir).unwrap();
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(10));
+    for (name, _) in &files {
+        let path = dir.join(name);
+        group.bench_function(*name, |b| b.iter(|| loopback.transfer(black_box(&path)).unwrap()));
+    }
+    group.finish();
+    drop(loopback);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, transfer);
+criterion_main!(benches);
@@ -1,21 +1,26 @@
 use crate::app::AppEvent::*;
-use crate::config::Config;
+use crate::config::{Config, PeerRule};
 use crate::connections::*;
+use crate::discovery::{discovery_service, Beacon, DiscoveredPeer, BEACON_EXPIRY};
+use crate::identity::{fingerprint, short_fingerprint, Identity, KnownPeers, TrustCheck};
+use crate::ratelimit::{parse_rate, RateLimits};
 use chrono::Local;
 use color_eyre::Result;
+use image::imageops::FilterType;
+use image::RgbImage;
 use ratatui::buffer::Buffer;
 use ratatui::crossterm::event;
 use ratatui::crossterm::event::{
     DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyModifiers,
 };
-use ratatui::layout::{Constraint, Layout, Margin, Rect};
+use ratatui::layout::{Constraint, Flex, Layout, Margin, Rect};
 use ratatui::prelude::{Line, Widget};
 use ratatui::style::{Color, Style};
 use ratatui::symbols::merge::MergeStrategy::Fuzzy;
 use ratatui::text::Span;
 use ratatui::widgets::{
-    Block, Padding, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState, StatefulWidget,
-    Wrap,
+    Block, Clear, Padding, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState,
+    StatefulWidget, Wrap,
 };
 use ratatui::{DefaultTerminal, Frame};
 use size::Size;
@@ -27,14 +32,16 @@ use std::net::{Shutdown, TcpStream};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc};
-use std::thread::{sleep, spawn, JoinHandle};
-use std::time::Duration;
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
 use ratatui::prelude::Color::*;
 
 pub(crate) const COMMAND: Style = Style::new().yellow();
 pub(crate) const ERROR: Style = Style::new().red();
 pub(crate) const INFO: Style = Style::new().dark_gray();
+///Minimum time between measurements of a transfer's speed
+const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
 
 type Connections = Vec<Arc<Connection>>;
 
@@ -42,9 +49,121 @@ type Connections = Vec<Arc<Connection>>;
 pub(crate) struct Download {
     pub(crate) connection: Arc<Connection>,
     pub(crate) id: u64,
+    ///Id the peer uses for the transfer
+    pub(crate) transfer_id: u64,
+    ///Offered file name while pending, path of the file being written once active
     pub(crate) path: String,
     pub(crate) progress: Size,
-    pub(crate) size: Size
+    pub(crate) size: Size,
+    pub(crate) speed: TransferSpeed,
+    pub(crate) state: TransferState
+}
+
+///A file or directory offered to a peer, removed once they reject it or it's sent
+#[derive(Debug)]
+pub(crate) struct Upload {
+    pub(crate) connection: Arc<Connection>,
+    ///Id sent to the peer with the offer
+    pub(crate) id: u64,
+    pub(crate) path: Arc<PathBuf>,
+    ///What's being sent, see [`read_tree`]
+    pub(crate) entries: Arc<Vec<TreeEntry>>,
+    pub(crate) progress: Size,
+    pub(crate) size: u64,
+    pub(crate) speed: TransferSpeed,
+    pub(crate) state: TransferState,
+    ///Shared with the thread sending the file once accepted
+    pub(crate) control: Arc<UploadControl>
+}
+
+///An image sent or received with `/img`, shown inline and in the image viewer
+#[derive(Debug)]
+struct InlineImage {
+    ///Address of the peer that sent it and its id for the transfer of the full image,
+    ///[`None`] if we sent it
+    source: Option<(String, u64)>,
+    preview: ImagePreview,
+    ///Where the full image is, once it's downloaded
+    path: Option<PathBuf>
+}
+
+///State of the image viewer opened with Ctrl+O
+#[derive(Debug)]
+struct ImageViewer {
+    ///Index in `images` of the image shown
+    index: usize,
+    ///The full image shrunk to fit the terminal,
+    ///[`None`] if it isn't downloaded so only the thumbnail can be shown
+    image: Option<RgbImage>
+}
+
+///An address being connected to in the background, shown in the Peers panel until it connects
+///or gives up, see [`connect_with_retries`]
+#[derive(Debug)]
+struct PendingConnection {
+    addr: String,
+    ///Fingerprint and nick of the peer if it's reconnecting to one that dropped
+    peer: Option<(String, Option<String>)>,
+    ///Attempts that failed so far
+    attempts: u32,
+    ///When the next attempt is made, [`None`] while the first one is made
+    next_attempt: Option<Instant>,
+    ///Set to stop trying
+    cancelled: Arc<AtomicBool>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransferState {
+    ///Offered, but not yet accepted
+    Pending,
+    Active,
+    ///Paused with `/pause` by either peer
+    Paused
+}
+
+///Speed of a transfer, smoothed over the progress updates it gets
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TransferSpeed {
+    bytes_per_sec: f64,
+    last_progress: u64,
+    last_update: Instant
+}
+
+impl TransferSpeed {
+    pub(crate) fn new(progress: u64) -> Self {
+        Self { bytes_per_sec: 0.0, last_progress: progress, last_update: Instant::now() }
+    }
+
+    ///Records that `progress` bytes have been transferred so far
+    fn update(&mut self, progress: u64) {
+        let elapsed = self.last_update.elapsed();
+        //progress updates come much more often than this, measuring each one would be jittery
+        if elapsed < SPEED_SAMPLE_INTERVAL {
+            return;
+        }
+        let sample = progress.saturating_sub(self.last_progress) as f64 / elapsed.as_secs_f64();
+        self.bytes_per_sec = if self.bytes_per_sec == 0.0 {
+            sample
+        } else {
+            self.bytes_per_sec * 0.7 + sample * 0.3
+        };
+        self.last_progress = progress;
+        self.last_update = Instant::now();
+    }
+
+    ///Returns the speed and time left to reach `size` bytes,
+    ///or nothing if there hasn't been enough progress to measure yet
+    fn display(&self, size: u64) -> String {
+        if self.bytes_per_sec < 1.0 {
+            return String::new();
+        }
+        let left = size.saturating_sub(self.last_progress) as f64 / self.bytes_per_sec;
+        format!(
+            ", {}/s, {} left",
+            Size::from_bytes(self.bytes_per_sec as u64),
+            format_duration(Duration::from_secs_f64(left))
+        )
+    }
 }
 
 ///Events for updating the app state
@@ -55,8 +174,8 @@ pub(crate) enum AppEvent {
     ///Event containing a [`Line<'static>`]
     MessageEvent(Line<'static>),
     ErrorEvent(String),
-    ///Event containing a [`TcpStream`]
-    NewStream(TcpStream),
+    ///Event containing a [`TcpStream`] and whether we connected to the peer
+    NewStream(TcpStream, bool),
     ///Event containing an [`Arc<Connection>`]
     ConnectionEvent(Arc<Connection>),
     ///Event containing the address of a peer that disconnected as a [`String`]
@@ -64,99 +183,192 @@ pub(crate) enum AppEvent {
     ///Event containing a listen address as a [`String`],
     ///used for updating the local username when none is set
     ListenEvent(String),
-    ///Event containing a new [`Download`]
+    ///Event containing a [`Download`] offered by a peer, waiting to be accepted or rejected
+    OfferEvent(Download),
+    ///Event containing a peer address, the id of an offered [`Upload`],
+    ///and the offset to start sending from if the peer accepted it
+    OfferAnswerEvent(String, u64, Option<u64>),
+    ///Event containing a peer address, an upload id and a progress value in bytes
+    UploadProgressEvent(String, u64, u64),
+    ///Event containing a peer address and the id of an [`Upload`] that was sent
+    UploadCompleteEvent(String, u64),
+    ///Event containing a peer address, the id of an [`Upload`] that failed, the error,
+    ///and whether it failed sending to the peer so it can resume
+    UploadFailedEvent(String, u64, String, bool),
+    ///Event containing a peer address, a transfer id, whether it's an [`Upload`],
+    ///and what the peer did to it
+    TransferControlEvent(String, u64, bool, TransferAction),
+    ///Event containing the peer that sent an [`ImagePreview`]
+    ///and its id for the transfer of the full image
+    ImageEvent(Arc<Connection>, u64, ImagePreview),
+    ///Event containing a peer address, the id of an [`Upload`] with a corrupted piece,
+    ///and the offset to send it again from
+    ResendEvent(String, u64, u64),
+    ///Event containing a new [`Download`], replaces any pending one with the same id
     DownloadEvent(Download),
     ///Event containing a download id and a progress value in bytes as [`u64`]s
     DownloadProgressEvent(u64, u64),
     ///Event containing a download id to remove as [`u64`]
     DownloadCompleteEvent(u64),
+    ///Event containing a peer address, the peer's id for a completed download,
+    ///and where it was saved
+    FileReceivedEvent(String, u64, PathBuf),
+    ///Event containing an address being connected to, the attempts that failed so far,
+    ///and when the next one is made, sent every second while waiting to update the countdown
+    RetryEvent(String, u32, Instant),
+    ///Event containing an address that stopped being connected to
+    ///because it connected, gave up or was cancelled
+    ConnectDoneEvent(String),
+    ///Event containing the address of a peer that answered a ping, the round-trip time,
+    ///and whether the ping was sent with `/ping`
+    PongEvent(String, Duration, bool),
+    ///Event containing an instance found on the local network, replaces any with its fingerprint
+    DiscoveryEvent(DiscoveredPeer),
     /////Generic event for forcing the app to render
     //Update
 }
 
 ///Struct to store the app state
 #[derive(Debug)]
-pub(crate) struct App<'a> {
+pub struct App<'a> {
+    ///What this instance announces on the local network, see [`Config::discovery`]
+    beacon: Arc<RwLock<Beacon>>,
     color: Color,
-    config: Config,
+    config: Arc<RwLock<Config>>,
     connections: Connections,
+    ///Instances found on the local network, in the order they were first found
+    discovered: Vec<DiscoveredPeer>,
     downloads: Vec<Download>,
+    ///Offered files waiting for an answer or being sent
+    uploads: Vec<Upload>,
+    ///Files that were being sent when the peer disconnected, as `(fingerprint, path)`,
+    ///offered again when the peer reconnects so the download can resume
+    interrupted_uploads: Vec<(String, Arc<PathBuf>)>,
     handles: Vec<JoinHandle<Result<()>>>,
+    ///Threads saying goodbye to peers, waited for before quitting
+    goodbyes: Vec<JoinHandle<Result<()>>>,
+    identity: Arc<Identity>,
+    images: Vec<InlineImage>,
     ///`(input, selection index)`
     input_buf: (Vec<char>, usize),
+    known_peers: KnownPeers,
     log_file: Option<fs::File>,
     listen_addr: String,
+    ///Addresses of all listeners, sent to peers in the handshake
+    listen_addrs: Arc<RwLock<Vec<String>>>,
     messages: Vec<Line<'a>>,
     nick: Option<String>,
+    ///Addresses being connected or reconnected to in the background
+    pending_connections: Vec<PendingConnection>,
+    ///Limits for file transfers with all peers together, changed with `/ratelimit`
+    rate_limits: Arc<RateLimits>,
     running: Arc<AtomicBool>,
     rx: Receiver<AppEvent>,
     scroll_pos: Cell<usize>,
     show_peers: bool,
+    ///Whether the list of instances opened with `/discover` is shown
+    show_discovered: bool,
     terminal_size: (u16, u16),
-    tx: Sender<AppEvent>
+    tx: Sender<AppEvent>,
+    viewer: Option<ImageViewer>
 }
 
 impl App<'static> {
     ///Creates a new [`App`] instance with the given [`Config`]
-    pub(crate) fn new(config: Config) -> Result<Self> {
+    pub fn new(config: Config) -> Result<Self> {
         let (tx, rx) = mpsc::channel::<AppEvent>();
-        let listen_addr = format!("{}:{}", config.listen_ips[0], config.listen_ports[0]);
+        let listen_addr = join_ip_port(&config.listen_ips[0], config.listen_ports[0]);
         let log_file = if config.log_messages {
             if !fs::exists(&config.log_path)? {
                 fs::File::create_new(&config.log_path)?;
             }
-            Some(fs::OpenOptions::new().write(true).append(true).open(&config.log_path)?)
+            Some(fs::OpenOptions::new().append(true).open(&config.log_path)?)
         } else {
             None
         };
+        let identity = Arc::new(Identity::load_or_generate(&config.identity_path)?);
+        let beacon = Beacon {
+            nick: config.nick.clone(),
+            fingerprint: fingerprint(&identity.public_key()),
+            listen_addrs: vec![]
+        };
         Ok(App {
+            beacon: Arc::new(RwLock::new(beacon)),
             color: random_color(),
             connections: vec![],
+            discovered: vec![],
             downloads: vec![],
+            uploads: vec![],
+            interrupted_uploads: vec![],
             handles: vec![],
+            goodbyes: vec![],
+            identity,
+            images: vec![],
             input_buf: (vec![], 0),
+            known_peers: KnownPeers::load(&config.known_peers_path),
             listen_addr,
+            listen_addrs: Arc::new(RwLock::new(vec![])),
             log_file,
             messages: vec![],
             nick: config.nick.clone(),
-            config,
+            pending_connections: vec![],
+            rate_limits: Arc::new(
+                RateLimits::new(config.max_upload_rate, config.max_download_rate)
+            ),
+            config: Arc::new(RwLock::new(config)),
             running: Arc::new(AtomicBool::new(true)),
             rx,
             scroll_pos: Cell::new(0),
             show_peers: true,
+            show_discovered: false,
             terminal_size: ratatui::crossterm::terminal::size()?,
-            tx
+            tx,
+            viewer: None
         })
     }
 
     ///Runs [`App`] in `terminal`
-    pub(crate) fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+    pub fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
         let mut stdout = stdout();
         ratatui::crossterm::execute!(stdout, EnableBracketedPaste)?;
         let t = self.tx.clone();
         let r = self.running.clone();
         self.handles.push(spawn(move || -> Result<()> { input_listener(t, r) }));
 
-        if self.config.listen_ips[0] == "all" {
-            self.config.listen_ips = local_ipv4_addrs();
+        let mut config = self.config.write().unwrap();
+        if config.listen_ips[0] == "all" {
+            config.listen_ips = local_ip_addrs();
         }
+        let listen_ips = config.listen_ips.clone();
+        let listen_ports = config.listen_ports.clone();
+        let startup_connections = config.startup_connections.clone();
+        let discovery = config.discovery;
+        drop(config);
 
-        for ip in &self.config.listen_ips {
-            for port in &self.config.listen_ports {
-                let addr = format!("{}:{}", ip, port);
+        if discovery {
+            let t = self.tx.clone();
+            let r = self.running.clone();
+            let b = self.beacon.clone();
+            self.handles.push(spawn(move || discovery_service(t, r, b)));
+        }
+
+        for ip in &listen_ips {
+            for port in &listen_ports {
+                let addr = join_ip_port(ip, *port);
                 let t = self.tx.clone();
+                let c = self.config.clone();
                 self.handles.push(spawn(move || -> Result<()> {
-                    connection_listener(t, &addr)
+                    connection_listener(t, c, &addr)
                 }));
             }
         }
 
-        for addr in &self.config.startup_connections.clone() {
+        for addr in &startup_connections {
             self.connect(addr)?;
         }
 
         while self.running.load(Ordering::Relaxed) {
-            terminal.draw(|mut frame| self.render(&mut frame))?;
+            terminal.draw(|frame| self.render(frame))?;
             self.update()?;
         }
 
@@ -167,7 +379,7 @@ impl App<'static> {
     ///Updates the [`App`] state
     fn update(&mut self) -> Result<()> {
         let recv = self.rx.recv()?;
-        if self.config.debug {
+        if self.config.read().unwrap().debug {
             self.debug(&recv)?;
         }
         match recv {
@@ -180,27 +392,119 @@ impl App<'static> {
             ErrorEvent(error) => {
                 self.display_error(&error)?;
             }
-            NewStream(stream) => {
+            NewStream(stream, outgoing) => {
                 let t = self.tx.clone();
                 let r = self.running.clone();
-                self.handles.push(spawn(move || connection_handler(t, r, stream)));
+                let c = self.config.clone();
+                let l = self.listen_addrs.clone();
+                let i = self.identity.clone();
+                let rl = self.rate_limits.clone();
+                self.handles.push(spawn(move || {
+                    connection_handler(t, r, c, l, i, rl, stream, outgoing)
+                }));
             }
             ConnectionEvent(connection) => {
+                //the peer got back to us first
+                self.pending_connections.retain(|p| {
+                    let same = p.peer.as_ref().is_some_and(|(f, _)| *f == connection.fingerprint);
+                    if same {
+                        p.cancelled.store(true, Ordering::Relaxed);
+                    }
+                    !same
+                });
                 self.handle_new_connection(connection)?;
             }
+            RetryEvent(addr, attempts, next_attempt) => {
+                if let Some(p) = self.pending_connections.iter_mut().find(|p| p.addr == addr) {
+                    p.attempts = attempts;
+                    p.next_attempt = Some(next_attempt);
+                }
+            }
+            ConnectDoneEvent(addr) => {
+                self.pending_connections.retain(|p| p.addr != addr);
+            }
+            PongEvent(peer_addr, rtt, requested) => {
+                if requested && let Some(c) = self.get_connection(&peer_addr) {
+                    let mut line = c.display_peer(false);
+                    line.push_span(Span::styled(
+                        format!(" answered in {:.1}ms", rtt.as_secs_f64() * 1000.0), INFO
+                    ));
+                    self.display_msg(&line)?;
+                }
+            }
             DisconnectionEvent(peer_addr) => {
-                self.disconnect(&peer_addr, false)?;
+                self.disconnect(&peer_addr, false, None)?;
             }
             ListenEvent(listen_addr) => {
+                self.listen_addrs.write().unwrap().push(listen_addr.clone());
+                self.beacon.write().unwrap().listen_addrs.push(listen_addr.clone());
                 self.listen_addr = listen_addr;
             }
+            OfferEvent(download) => {
+                self.handle_offer(download)?;
+            }
+            OfferAnswerEvent(peer_addr, id, offset) => {
+                self.handle_offer_answer(&peer_addr, id, offset)?;
+            }
+            UploadProgressEvent(peer_addr, id, progress) => {
+                if let Some(u) = self.find_upload(&peer_addr, id) {
+                    let u = &mut self.uploads[u];
+                    u.progress = Size::from_bytes(progress);
+                    u.speed.update(progress);
+                }
+            }
+            UploadCompleteEvent(peer_addr, id) => {
+                if let Some(u) = self.find_upload(&peer_addr, id) {
+                    let upload = self.uploads.remove(u);
+                    let mut message = Line::from(Span::styled(format!(
+                        "Sent \"{}\" to ",
+                        upload.path.file_name().unwrap_or_default().to_string_lossy()
+                    ), INFO));
+                    message.spans.extend(upload.connection.display_peer(false).spans);
+                    self.display_msg(&message)?;
+                }
+            }
+            UploadFailedEvent(peer_addr, id, error, interrupted) => {
+                self.handle_upload_failure(&peer_addr, id, &error, interrupted)?;
+            }
+            TransferControlEvent(peer_addr, id, upload, action) => {
+                self.handle_transfer_control(&peer_addr, id, upload, action)?;
+            }
+            ImageEvent(connection, id, preview) => {
+                let mut message = connection.display_peer(false);
+                message.push_span(Span::styled(format!(
+                    " sent an image \"{}\" ({}x{}), Ctrl+O to view",
+                    preview.name, preview.width, preview.height
+                ), INFO));
+                self.display_msg(&message)?;
+                self.display_image(InlineImage {
+                    source: Some((connection.peer_addr.clone(), id)),
+                    preview,
+                    path: None
+                });
+            }
+            ResendEvent(peer_addr, id, offset) => {
+                if let Some(u) = self.find_upload(&peer_addr, id) {
+                    self.uploads[u].control.resend_from.lock().unwrap().replace(offset);
+                } else if let Some(c) = self.get_connection(&peer_addr) {
+                    //everything was already sent, stop the peer waiting for the rest
+                    self.handles.push(spawn(move || -> Result<()> {
+                        send_control(&c, id, true, TransferAction::Cancel)
+                    }));
+                }
+            }
             DownloadEvent(download) => {
-                self.downloads.push(download);
+                if let Some(d) = self.downloads.iter_mut().find(|d| d.id == download.id) {
+                    *d = download;
+                } else {
+                    self.downloads.push(download);
+                }
             }
             DownloadProgressEvent(id, progress) => {
                 if let Some(idx) = self.downloads.iter().position(|d| d.id == id) {
                     let d = &mut self.downloads[idx];
                     d.progress = Size::from_bytes(progress);
+                    d.speed.update(progress);
                 }
             }
             DownloadCompleteEvent(id) => {
@@ -208,6 +512,20 @@ impl App<'static> {
                     self.downloads.remove(idx);
                 }
             }
+            DiscoveryEvent(peer) => {
+                if let Some(d) = self.discovered.iter_mut()
+                    .find(|d| d.fingerprint == peer.fingerprint) {
+                    *d = peer;
+                } else {
+                    self.discovered.push(peer);
+                }
+            }
+            FileReceivedEvent(peer_addr, id, path) => {
+                if let Some(image) = self.images.iter_mut()
+                    .find(|i| i.source.as_ref().is_some_and(|s| s.0 == peer_addr && s.1 == id)) {
+                    image.path = Some(path);
+                }
+            }
             //Update => ()
         }
 
@@ -215,24 +533,428 @@ impl App<'static> {
     }
 
     fn handle_new_connection(&mut self, connection: Arc<Connection>) -> Result<()> {
+        //when both peers reconnect at once each ends up with two connections to the other,
+        //both keep the one opened by the peer with the lower fingerprint
+        let own = fingerprint(&self.identity.public_key());
+        if let Some(existing) = self.connections.iter()
+            .find(|c| c.fingerprint == connection.fingerprint && c.fingerprint != own)
+            .cloned() {
+            let preferred = |c: &Connection| c.outgoing == (own < c.fingerprint);
+            if !preferred(&connection) || preferred(&existing) {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+                return Ok(());
+            }
+            self.replace_connection(&existing);
+        }
         let mut line = connection.display_peer(false);
         line.push_span(" joined");
+        let mut info = format!(" (v{}", connection.peer_version);
+        if !connection.peer_listen_addrs.is_empty() {
+            info.push_str(&format!(", listening on {}", connection.peer_listen_addrs.join(", ")));
+        }
+        line.push_span(Span::styled(info + ")", INFO));
         self.display_msg(&line)?;
+
+        let host = connection.known_host();
+        match self.known_peers.check(&host, &connection.fingerprint) {
+            Ok(TrustCheck::New) => {
+                self.display_msg(&Line::from(Span::styled(format!(
+                    "Added {host} to known peers, fingerprint {}",
+                    short_fingerprint(&connection.fingerprint)
+                ), INFO)))?;
+            }
+            Ok(TrustCheck::Known) => {
+                if self.known_peers.is_verified(&host, &connection.fingerprint) {
+                    connection.verified.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok(TrustCheck::Changed(known)) => {
+                for warning in [
+                    "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@".to_string(),
+                    format!("WARNING: THE IDENTITY KEY OF {host} HAS CHANGED!"),
+                    "Someone could be impersonating this peer, or they reinstalled.".to_string(),
+                    format!("Known fingerprint: {}", short_fingerprint(&known)),
+                    format!("New fingerprint:   {}", short_fingerprint(&connection.fingerprint)),
+                    "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@".to_string(),
+                ] {
+                    self.display_msg(&Line::from(Span::styled(warning, ERROR.bold())))?;
+                }
+            }
+            Err(e) => self.display_error(&format!("Failed to save known peers: {e}"))?
+        }
         if let Some(n) = self.nick.clone() {
             let c = connection.clone();
             self.handles.push(spawn(move || -> Result<()> {
                 send_msg(c, Arc::new(format!("/n {n}")), &MessageType::Command)
             }));
         }
-        Ok(self.connections.push(connection))
+        let (resumed, interrupted) = self.interrupted_uploads.drain(..)
+            .partition(|(fingerprint, _)| *fingerprint == connection.fingerprint);
+        self.interrupted_uploads = interrupted;
+        for (_, path) in resumed {
+            if path.exists() {
+                self.offer_file(connection.clone(), &path, None)?;
+            }
+        }
+        self.connections.push(connection);
+        Ok(())
+    }
+
+    ///Quietly closes `existing` in favour of a new connection to the same peer,
+    ///its uploads are offered again on the new one, where the peer resumes them
+    fn replace_connection(&mut self, existing: &Arc<Connection>) {
+        let _ = existing.stream.shutdown(Shutdown::Both);
+        self.connections.retain(|c| !Arc::ptr_eq(c, existing));
+        self.downloads.retain(|d| !Arc::ptr_eq(&d.connection, existing));
+        self.uploads.retain(|u| {
+            let keep = !Arc::ptr_eq(&u.connection, existing);
+            if !keep {
+                u.control.cancelled.store(true, Ordering::Relaxed);
+                self.interrupted_uploads.push((u.connection.fingerprint.clone(), u.path.clone()));
+            }
+            keep
+        });
+    }
+
+    ///Adds an offered download, and accepts it if it matches an auto-accept rule
+    ///or continues an interrupted download
+    fn handle_offer(&mut self, download: Download) -> Result<()> {
+        let resume = download.connection.offers.lock().unwrap()
+            .get(&download.transfer_id)
+            .is_some_and(|o| o.partial.is_some());
+        let auto_accept = resume || {
+            let config = self.config.read().unwrap();
+            let c = &download.connection;
+            c.peer_ip().is_some_and(|ip| config.auto_accepts(ip, &c.fingerprint))
+                || config.auto_accept_size.is_some_and(|s| download.size.bytes() <= s as i64)
+        };
+        let mut message = download.connection.display_peer(false);
+        message.push_span(Span::styled(if resume {
+            format!(
+                " resumed \"{}\" ({}) at {}", download.path, download.size, download.progress
+            )
+        } else {
+            format!(" offered \"{}\" ({})", download.path, download.size)
+        }, INFO));
+        if !auto_accept {
+            message.push_span(Span::styled(
+                format!(", /accept {0} or /reject {0}", download.id), INFO
+            ));
+        }
+        self.display_msg(&message)?;
+        self.downloads.push(download);
+        if auto_accept {
+            self.accept_download(self.downloads.len() - 1);
+        }
+
+        Ok(())
+    }
+
+    ///Starts sending the offered [`Upload`] from `offset` if it was accepted,
+    ///otherwise forgets it
+    fn handle_offer_answer(
+        &mut self,
+        peer_addr: &str,
+        id: u64,
+        offset: Option<u64>
+    ) -> Result<()> {
+        let Some(idx) = self.find_upload(peer_addr, id) else {
+            return Ok(());
+        };
+        let upload = &self.uploads[idx];
+        let name = upload.path.file_name().unwrap_or_default().to_string_lossy();
+        let mut message = upload.connection.display_peer(false);
+        message.push_span(Span::styled(match offset {
+            Some(0) => format!(" accepted \"{name}\""),
+            Some(o) => format!(" accepted \"{name}\", resuming at {}", Size::from_bytes(o)),
+            None => format!(" rejected \"{name}\"")
+        }, INFO));
+        self.display_msg(&message)?;
+        let Some(offset) = offset else {
+            self.uploads.remove(idx);
+            return Ok(());
+        };
+        let upload = &mut self.uploads[idx];
+        upload.state = TransferState::Active;
+        upload.progress = Size::from_bytes(offset);
+        upload.speed = TransferSpeed::new(offset);
+        let c = upload.connection.clone();
+        let p = upload.path.clone();
+        let entries = upload.entries.clone();
+        let control = upload.control.clone();
+        let t = self.tx.clone();
+        self.handles.push(spawn(move || -> Result<()> {
+            let peer_addr = c.peer_addr.clone();
+            match send_file(t.clone(), c, p, entries, id, offset, control) {
+                Ok(()) => t.send(UploadCompleteEvent(peer_addr, id))?,
+                Err(e) => {
+                    let interrupted = e.is::<StreamError>();
+                    t.send(UploadFailedEvent(peer_addr, id, e.to_string(), interrupted))?
+                }
+            }
+
+            Ok(())
+        }));
+
+        Ok(())
+    }
+
+    ///Reports an upload that stopped because of an error,
+    ///and keeps it to offer again if the peer reconnects if it was `interrupted` sending to it
+    fn handle_upload_failure(
+        &mut self,
+        peer_addr: &str,
+        id: u64,
+        error: &str,
+        interrupted: bool
+    ) -> Result<()> {
+        let Some(idx) = self.find_upload(peer_addr, id) else {
+            return Ok(());
+        };
+        let upload = self.uploads.remove(idx);
+        if interrupted {
+            let fingerprint = upload.connection.fingerprint.clone();
+            self.interrupted_uploads.push((fingerprint, upload.path.clone()));
+        }
+        let mut message = Line::from(Span::styled(format!(
+            "Failed to send \"{}\" to ",
+            upload.path.file_name().unwrap_or_default().to_string_lossy()
+        ), ERROR));
+        message.spans.extend(upload.connection.display_peer(false).spans);
+        message.push_span(Span::styled(format!(": {error}"), ERROR));
+        self.display_msg(&message)?;
+        //let the peer know to stop waiting, keeping what they have so far to resume from
+        let c = upload.connection;
+        self.handles.push(spawn(move || -> Result<()> {
+            send_control(&c, id, true, TransferAction::Cancel)
+        }));
+
+        Ok(())
+    }
+
+    ///Returns the index in `uploads` of the upload to `peer_addr` with the given `id`
+    fn find_upload(&self, peer_addr: &str, id: u64) -> Option<usize> {
+        self.uploads.iter().position(|u| u.id == id && u.connection.peer_addr == peer_addr)
+    }
+
+    ///Accepts the pending download at `idx` in `downloads`
+    fn accept_download(&mut self, idx: usize) {
+        let download = &mut self.downloads[idx];
+        download.state = TransferState::Active;
+        let mut offset = 0;
+        if let Some(offer) = download.connection.offers.lock().unwrap()
+            .get_mut(&download.transfer_id) {
+            offer.accepted = true;
+            offset = offer.offset();
+        }
+        let c = download.connection.clone();
+        let id = download.transfer_id;
+        self.handles.push(spawn(move || -> Result<()> { send_answer(c, id, Some(offset)) }));
+    }
+
+    ///Rejects and removes the pending download at `idx` in `downloads`
+    fn reject_download(&mut self, idx: usize) {
+        let download = self.downloads.remove(idx);
+        download.connection.offers.lock().unwrap().remove(&download.transfer_id);
+        let c = download.connection;
+        let id = download.transfer_id;
+        self.handles.push(spawn(move || -> Result<()> { send_answer(c, id, None) }));
+    }
+
+    ///Pauses, resumes or cancels the download or upload with the id in `arg`,
+    ///and tells the peer so they do the same
+    fn control_transfer(&mut self, arg: &str, action: TransferAction) -> Result<()> {
+        let id = arg.parse::<u64>().ok();
+        if let Some(idx) = self.downloads.iter().position(|d| Some(d.id) == id) {
+            let download = &mut self.downloads[idx];
+            let new_state = match (download.state, action) {
+                (TransferState::Pending, TransferAction::Cancel | TransferAction::Discard) => {
+                    self.reject_download(idx);
+                    return Ok(());
+                }
+                (TransferState::Pending, _) => {
+                    return self.display_error(&format!("Download {arg} hasn't started"));
+                }
+                (TransferState::Paused, TransferAction::Pause) => {
+                    return self.display_error(&format!("Download {arg} is already paused"));
+                }
+                (TransferState::Active, TransferAction::Resume) => {
+                    return self.display_error(&format!("Download {arg} isn't paused"));
+                }
+                (_, TransferAction::Pause) => TransferState::Paused,
+                (_, TransferAction::Resume) => TransferState::Active,
+                (_, TransferAction::Cancel | TransferAction::Discard) => {
+                    let download = self.downloads.remove(idx);
+                    let received = download.connection.offers.lock().unwrap()
+                        .remove(&download.transfer_id);
+                    if let Some(received) = received
+                        && let Err(e) = received.cancel(action == TransferAction::Discard) {
+                        self.display_error(&format!("Failed to clean up the partial file: {e}"))?;
+                    }
+                    let c = download.connection.clone();
+                    let id = download.transfer_id;
+                    self.handles.push(spawn(move || -> Result<()> {
+                        send_control(&c, id, false, action)
+                    }));
+                    return self.display_msg(&Line::from(Span::styled(
+                        format!("Cancelled download of \"{}\"", download.path), INFO
+                    )));
+                }
+            };
+            download.state = new_state;
+            download.speed = TransferSpeed::new(download.progress.bytes() as u64);
+            let c = download.connection.clone();
+            let id = download.transfer_id;
+            self.handles.push(spawn(move || -> Result<()> { send_control(&c, id, false, action) }));
+        } else if let Some(idx) = self.uploads.iter().position(|u| Some(u.id) == id) {
+            let upload = &mut self.uploads[idx];
+            match (upload.state, action) {
+                (TransferState::Pending, TransferAction::Pause | TransferAction::Resume) => {
+                    return self.display_error(&format!("Upload {arg} hasn't started"));
+                }
+                (TransferState::Paused, TransferAction::Pause) => {
+                    return self.display_error(&format!("Upload {arg} is already paused"));
+                }
+                (TransferState::Active, TransferAction::Resume) => {
+                    return self.display_error(&format!("Upload {arg} isn't paused"));
+                }
+                _ => ()
+            }
+            let c = upload.connection.clone();
+            let id = upload.id;
+            self.handles.push(spawn(move || -> Result<()> { send_control(&c, id, true, action) }));
+            if let Some(name) = self.apply_upload_action(idx, action) {
+                self.display_msg(&Line::from(Span::styled(
+                    format!("Cancelled upload of \"{name}\""), INFO
+                )))?;
+            }
+        } else {
+            self.display_error(&format!("No download or upload with id {arg}"))?;
+        }
+
+        Ok(())
+    }
+
+    ///Applies `action` to the upload at `idx` in `uploads`,
+    ///returns the name of the file if the upload was cancelled and removed
+    fn apply_upload_action(&mut self, idx: usize, action: TransferAction) -> Option<String> {
+        let upload = &mut self.uploads[idx];
+        match action {
+            TransferAction::Pause => {
+                upload.control.paused.store(true, Ordering::Relaxed);
+                upload.state = TransferState::Paused;
+            }
+            TransferAction::Resume => {
+                upload.control.paused.store(false, Ordering::Relaxed);
+                upload.state = TransferState::Active;
+                //don't count the time spent paused
+                upload.speed = TransferSpeed::new(upload.progress.bytes() as u64);
+            }
+            TransferAction::Cancel | TransferAction::Discard => {
+                upload.control.cancelled.store(true, Ordering::Relaxed);
+                let name = upload.path.file_name().unwrap_or_default().to_string_lossy();
+                let name = name.to_string();
+                self.uploads.remove(idx);
+                return Some(name);
+            }
+        }
+
+        None
+    }
+
+    ///Applies a [`TransferAction`] the peer sent for one of our transfers
+    fn handle_transfer_control(
+        &mut self,
+        peer_addr: &str,
+        id: u64,
+        upload: bool,
+        action: TransferAction
+    ) -> Result<()> {
+        let (connection, name) = if upload {
+            let Some(idx) = self.find_upload(peer_addr, id) else {
+                return Ok(());
+            };
+            let connection = self.uploads[idx].connection.clone();
+            let name = self.uploads[idx].path.file_name().unwrap_or_default()
+                .to_string_lossy().to_string();
+            self.apply_upload_action(idx, action);
+            (connection, name)
+        } else {
+            let Some(idx) = self.downloads.iter().position(
+                |d| d.transfer_id == id && d.connection.peer_addr == peer_addr
+            ) else {
+                return Ok(());
+            };
+            let download = &mut self.downloads[idx];
+            let connection = download.connection.clone();
+            let name = download.path.clone();
+            match action {
+                TransferAction::Pause => download.state = TransferState::Paused,
+                TransferAction::Resume => {
+                    download.state = TransferState::Active;
+                    download.speed = TransferSpeed::new(download.progress.bytes() as u64);
+                }
+                TransferAction::Cancel | TransferAction::Discard => {
+                    self.downloads.remove(idx);
+                }
+            }
+            (connection, name)
+        };
+        let mut message = connection.display_peer(false);
+        message.push_span(Span::styled(match action {
+            TransferAction::Pause => format!(" paused \"{name}\""),
+            TransferAction::Resume => format!(" resumed \"{name}\""),
+            TransferAction::Cancel => format!(" cancelled \"{name}\""),
+            TransferAction::Discard => format!(" cancelled \"{name}\" and removed the partial file")
+        }, INFO));
+
+        self.display_msg(&message)
+    }
+
+    ///Returns the index in `downloads` of the pending download with the id in `arg`
+    fn find_pending_download(&self, arg: &str) -> Option<usize> {
+        let id = arg.parse::<u64>().ok()?;
+        self.downloads.iter().position(|d| d.id == id && d.state == TransferState::Pending)
     }
 
     ///Handles [crossterm] events, currently only key presses
     fn handle_input(&mut self, event: &Event) -> Result<()> {
+        if let Some(viewer) = &self.viewer
+            && let Event::Key(key) = event {
+            let index = viewer.index;
+            match key.code {
+                KeyCode::Left if index > 0 => self.open_viewer(index - 1),
+                KeyCode::Right if index + 1 < self.images.len() => self.open_viewer(index + 1),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.quit(None)?;
+                }
+                KeyCode::Esc | KeyCode::Enter | KeyCode::Char('o') => self.viewer = None,
+                _ => ()
+            }
+            return Ok(());
+        }
+        if self.show_discovered
+            && let Event::Key(key) = event {
+            match key.code {
+                KeyCode::Char(c @ '1'..='9') => {
+                    let index = c as usize - '1' as usize;
+                    if let Some(addr) = self.discovered_peers().get(index).map(|d| d.addr.clone()) {
+                        self.show_discovered = false;
+                        self.connect(&addr)?;
+                    }
+                }
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.quit(None)?;
+                }
+                KeyCode::Esc | KeyCode::Enter => self.show_discovered = false,
+                _ => ()
+            }
+            return Ok(());
+        }
         match event {
             Event::Key(key) => match key.code {
                 KeyCode::Esc => {
-                    self.running.store(false, Ordering::Relaxed);
+                    self.quit(None)?;
                 }
                 KeyCode::Tab => {
                     self.show_peers = !self.show_peers;
@@ -300,7 +1022,11 @@ impl App<'static> {
                 }
                 KeyCode::Char(c) => {
                     if key.modifiers.contains(KeyModifiers::CONTROL) && c == 'c' {
-                        self.running.store(false, Ordering::Relaxed);
+                        self.quit(None)?;
+                    } else if key.modifiers.contains(KeyModifiers::CONTROL) && c == 'o' {
+                        if !self.images.is_empty() {
+                            self.open_viewer(self.images.len() - 1);
+                        }
                     } else {
                         self.input_buf.0.insert(self.input_buf.0.len() - self.input_buf.1, c);
                     }
@@ -403,15 +1129,29 @@ impl App<'static> {
     }
 
     fn handle_cmd(&mut self) -> Result<()> {
-        const COMMANDS: [&str; 8] = [
+        const COMMANDS: [&str; 22] = [
+            "/a,  /accept <ID>",
+            "/b,  /block <NICK|ADDRESS|IP|CIDR|FINGERPRINT>",
             "/c,  /connect <ADDRESS>",
+            "/ca, /cancel <ID> [keep]",
             "/d,  /disconnect <NICK|ADDRESS>",
             "/da, /disconnect_all",
+            "/di, /discover",
             "/h,  /help",
+            "/i,  /img <PATH>",
             "/m,  /msg <NICK|ADDRESS> <MESSAGE>",
             "/mf, /msg_file <NICK|ADDRESS> <FILEPATH>",
             "/n,  /nick <NICK>",
-            "/sf, /send_file <PATH>"
+            "/p,  /pause <ID>",
+            "/pi, /ping <NICK|ADDRESS>",
+            "/q,  /quit [REASON]",
+            "/r,  /reject <ID>",
+            "/re, /resume <ID>",
+            "/rl, /ratelimit [NICK|ADDRESS] <up|down> <RATE|off>",
+            "/sf, /send_file <PATH>",
+            "/t,  /trust <NICK|ADDRESS>",
+            "/u,  /unblock <IP|CIDR|FINGERPRINT>",
+            "/v,  /verify <NICK|ADDRESS>"
         ];
 
         self.display_input_msg(&MessageType::Command)?;
@@ -424,6 +1164,17 @@ impl App<'static> {
                 None
             };
             match cmd {
+                "/accept" | "/a" => {
+                    if let Some(a) = arg {
+                        if let Some(idx) = self.find_pending_download(a) {
+                            self.accept_download(idx);
+                        } else {
+                            self.display_error(&format!("No pending download with id {a}"))?;
+                        }
+                    } else {
+                        self.display_error("No download specified")?;
+                    }
+                }
                 "/connect" | "/c" => {
                     if let Some(a) = arg {
                         self.connect(a.trim())?;
@@ -434,7 +1185,13 @@ impl App<'static> {
                 "/disconnect" | "/d" => {
                     if let Some(a) = arg {
                         if let Some(addr) = self.find_peer_addr(a.trim()) {
-                            self.disconnect(&addr, true)?;
+                            self.disconnect(&addr, true, None)?;
+                        } else if let Some(idx) = self.find_pending_connection(a.trim()) {
+                            let p = self.pending_connections.remove(idx);
+                            p.cancelled.store(true, Ordering::Relaxed);
+                            self.display_msg(&Line::from(Span::styled(
+                                format!("Stopped connecting to {}", p.addr), INFO
+                            )))?;
                         } else {
                             self.display_error("Failed to disconnect, no such peer")?;
                         }
@@ -448,7 +1205,17 @@ impl App<'static> {
                         addrs.push(c.peer_addr.clone());
                     }
                     for addr in &addrs {
-                        self.disconnect(addr, true)?;
+                        self.disconnect(addr, true, None)?;
+                    }
+                    for p in self.pending_connections.drain(..) {
+                        p.cancelled.store(true, Ordering::Relaxed);
+                    }
+                }
+                "/discover" | "/di" => {
+                    if self.config.read().unwrap().discovery {
+                        self.show_discovered = true;
+                    } else {
+                        self.display_error("Discovery is off, turn it on with --discovery")?;
                     }
                 }
                 "/help" | "/h" => {
@@ -460,7 +1227,7 @@ impl App<'static> {
                     if let Some(a) = arg {
                         let mut args = a.splitn(2, ' ');
                         if let Some(addr) = args.next() && let Some(msg) = args.next() {
-                            if let Some(a) = self.find_peer_addr(&addr)
+                            if let Some(a) = self.find_peer_addr(addr)
                                 && let Some(c) = self.get_connection(&a) {
                                 let m = Arc::new(msg.trim().to_string());
                                 self.handles.push(spawn(move || -> Result<()> {
@@ -482,10 +1249,14 @@ impl App<'static> {
                         if let Some(addr) = args.next()
                             && let Some(file) = args.next()
                             && !file.is_empty() {
-                            if let Some(a) = self.find_peer_addr(&addr)
+                            let path = Path::new(file.trim());
+                            if let Some(a) = self.find_peer_addr(addr)
                                 && let Some(c) = self.get_connection(&a) {
-                                let p = Arc::new(PathBuf::from(file.trim()));
-                                self.handles.push(spawn(move || -> Result<()> { send_file(c, p) }));
+                                if path.exists() {
+                                    self.offer_file(c, path, None)?;
+                                } else {
+                                    self.display_error("No such file or directory")?;
+                                }
                             } else {
                                 self.display_error("Failed to send file, no such peer")?;
                             }
@@ -499,24 +1270,137 @@ impl App<'static> {
                 "/nick" | "/n" => {
                     if let Some(a) = arg {
                         let nick = a.trim().to_string();
-                        self.nick.replace(nick);
+                        self.nick.replace(nick.clone());
+                        self.beacon.write().unwrap().nick = Some(nick);
                         self.broadcast_input_msg(&MessageType::Command);
                     } else {
                         self.display_error("No nick specified")?;
                     }
                 }
+                "/reject" | "/r" => {
+                    if let Some(a) = arg {
+                        if let Some(idx) = self.find_pending_download(a) {
+                            self.reject_download(idx);
+                        } else {
+                            self.display_error(&format!("No pending download with id {a}"))?;
+                        }
+                    } else {
+                        self.display_error("No download specified")?;
+                    }
+                }
+                "/img" | "/i" => {
+                    if let Some(a) = arg {
+                        self.broadcast_image(Path::new(a))?;
+                    } else {
+                        self.display_error("No image specified")?;
+                    }
+                }
                 "/send_file" | "/sf" => {
                     if let Some(a) = arg {
                         let path = Path::new(a);
-                        if path.try_exists()? {
-                            self.broadcast_file(&path);
+                        if path.exists() {
+                            self.broadcast_file(path)?;
                         } else {
-                            self.display_error("No such file")?;
+                            self.display_error("No such file or directory")?;
                         }
                     } else {
                         self.display_error("No file specified")?;
                     }
                 }
+                "/trust" | "/t" => {
+                    if let Some(a) = arg {
+                        if let Some(addr) = self.find_peer_addr(a)
+                            && let Some(c) = self.get_connection(&addr) {
+                            if let Err(e) = self.known_peers.trust(&c.known_host(), &c.fingerprint) {
+                                return self.display_error(&format!("Failed to save known peers: {e}"));
+                            }
+                            c.verified.store(true, Ordering::Relaxed);
+                            let mut message = Line::from(Span::styled("Marked ", INFO));
+                            message.spans.extend(c.display_peer(false).spans);
+                            message.push_span(Span::styled(" as verified", INFO));
+                            self.display_msg(&message)?;
+                        } else {
+                            self.display_error("Failed to trust peer, no such peer")?;
+                        }
+                    } else {
+                        self.display_error("No peer specified")?;
+                    }
+                }
+                "/cancel" | "/ca" => {
+                    if let Some(a) = arg {
+                        let mut args = a.split_whitespace();
+                        let id = args.next().unwrap_or_default();
+                        //remove the partial file unless asked to keep it to resume from later
+                        let action = if args.next() == Some("keep") {
+                            TransferAction::Cancel
+                        } else {
+                            TransferAction::Discard
+                        };
+                        self.control_transfer(id, action)?;
+                    } else {
+                        self.display_error("No transfer specified")?;
+                    }
+                }
+                "/pause" | "/p" => {
+                    if let Some(a) = arg {
+                        self.control_transfer(a, TransferAction::Pause)?;
+                    } else {
+                        self.display_error("No transfer specified")?;
+                    }
+                }
+                "/ping" | "/pi" => {
+                    if let Some(a) = arg {
+                        if let Some(addr) = self.find_peer_addr(a)
+                            && let Some(c) = self.get_connection(&addr) {
+                            if c.supports(CAPABILITY_PING) {
+                                self.handles.push(spawn(move || c.ping(true)));
+                            } else {
+                                self.display_error("Failed to ping peer, it's too old to answer")?;
+                            }
+                        } else {
+                            self.display_error("Failed to ping peer, no such peer")?;
+                        }
+                    } else {
+                        self.display_error("No peer specified")?;
+                    }
+                }
+                "/quit" | "/q" => self.quit(arg)?,
+                "/resume" | "/re" => {
+                    if let Some(a) = arg {
+                        self.control_transfer(a, TransferAction::Resume)?;
+                    } else {
+                        self.display_error("No transfer specified")?;
+                    }
+                }
+                "/ratelimit" | "/rl" => {
+                    self.rate_limit(arg)?;
+                }
+                "/block" | "/b" => {
+                    if let Some(a) = arg {
+                        self.block(a)?;
+                    } else {
+                        self.display_error("No peer specified")?;
+                    }
+                }
+                "/unblock" | "/u" => {
+                    if let Some(a) = arg {
+                        self.unblock(a)?;
+                    } else {
+                        self.display_error("No rule specified")?;
+                    }
+                }
+                "/verify" | "/v" => {
+                    if let Some(a) = arg {
+                        if let Some(addr) = self.find_peer_addr(a)
+                            && let Some(c) = self.get_connection(&addr) {
+                            self.display_verification(&c)?;
+                        } else {
+                            self.display_error("Failed to verify peer, no such peer")?;
+                        }
+                    } else {
+                        self.display_error("No peer specified")?;
+                    }
+                }
                 _ => self.display_error(&format!("Unknown command: {cmd}"))?
             }
         }
@@ -524,42 +1408,207 @@ impl App<'static> {
         Ok(())
     }
 
+    ///Adds a deny rule for `target` and disconnects any peers it matches,
+    ///a connected peer is blocked by fingerprint so changing address doesn't get around it
+    fn block(&mut self, target: &str) -> Result<()> {
+        let rule = if let Some(addr) = self.find_peer_addr(target)
+            && let Some(c) = self.get_connection(&addr) {
+            PeerRule::Fingerprint(c.fingerprint.clone())
+        } else if let Some(rule) = PeerRule::parse(target) {
+            rule
+        } else {
+            return self.display_error(&format!(
+                "Failed to block {target}, not a peer, IP, CIDR range or fingerprint"
+            ));
+        };
+        if !self.config.write().unwrap().block(&rule) {
+            return self.display_error(&format!("{rule} is already blocked"));
+        }
+        let config = self.config.read().unwrap();
+        let blocked: Vec<String> = self.connections.iter()
+            .filter(|c| c.peer_ip().is_some_and(|ip| !config.is_allowed(ip, Some(&c.fingerprint))))
+            .map(|c| c.peer_addr.clone())
+            .collect();
+        drop(config);
+        for addr in &blocked {
+            self.disconnect(addr, true, None)?;
+        }
+        self.display_msg(&Line::from(Span::styled(format!("Blocked {rule}"), INFO)))?;
+        let saved = self.config.read().unwrap().save_rules();
+        if let Err(e) = saved {
+            self.display_error(&format!("Failed to save block to the config file: {e}"))?;
+        }
+
+        Ok(())
+    }
+
+    ///Removes the deny rule for `target`
+    fn unblock(&mut self, target: &str) -> Result<()> {
+        let Some(rule) = PeerRule::parse(target) else {
+            return self.display_error(&format!(
+                "Failed to unblock {target}, not an IP, CIDR range or fingerprint"
+            ));
+        };
+        if !self.config.write().unwrap().unblock(&rule) {
+            return self.display_error(&format!("{rule} isn't blocked"));
+        }
+        self.display_msg(&Line::from(Span::styled(format!("Unblocked {rule}"), INFO)))?;
+        let saved = self.config.read().unwrap().save_rules();
+        if let Err(e) = saved {
+            self.display_error(&format!("Failed to save unblock to the config file: {e}"))?;
+        }
+
+        Ok(())
+    }
+
+    ///Sets the upload or download rate for all peers or, if `args` starts with one, a single peer,
+    ///shows the current rates if there are no `args`
+    ///
+    ///Changes aren't saved to the config file
+    fn rate_limit(&mut self, args: Option<&str>) -> Result<()> {
+        let Some(args) = args else {
+            self.display_msg(&Line::from(Span::styled(format!(
+                "Upload rate {}, download rate {}",
+                self.rate_limits.upload.display(), self.rate_limits.download.display()
+            ), INFO)))?;
+            let limited: Vec<_> = self.connections.iter()
+                .filter(|c| c.rate_limits.upload.rate().is_some()
+                    || c.rate_limits.download.rate().is_some())
+                .cloned()
+                .collect();
+            for c in limited {
+                let mut message = Line::from(Span::raw("  "));
+                message.spans.extend(c.display_peer(true).spans);
+                message.push_span(Span::styled(format!(
+                    ": upload rate {}, download rate {}",
+                    c.rate_limits.upload.display(), c.rate_limits.download.display()
+                ), INFO));
+                self.display_msg(&message)?;
+            }
+            return Ok(());
+        };
+        let args: Vec<_> = args.split_whitespace().collect();
+        let is_direction = |arg| matches!(arg, "up" | "upload" | "down" | "download");
+        //everything after the direction is the rate, which can have spaces like "2 MB/s"
+        let (connection, direction, rate) = match args[..] {
+            [peer, direction, ref rate @ ..] if is_direction(direction) && !rate.is_empty() => {
+                if let Some(addr) = self.find_peer_addr(peer)
+                    && let Some(c) = self.get_connection(&addr) {
+                    (Some(c), direction, rate.join(" "))
+                } else {
+                    return self.display_error("Failed to set rate limit, no such peer");
+                }
+            }
+            [direction, ref rate @ ..] if !rate.is_empty() => (None, direction, rate.join(" ")),
+            _ => return self.display_error("Expected [NICK|ADDRESS] <up|down> <RATE|off>")
+        };
+        let Some(rate) = parse_rate(&rate) else {
+            return self.display_error(&format!("Invalid rate {rate}, expected e.g. 500KiB or off"));
+        };
+        let limits = connection.as_ref().map_or(&*self.rate_limits, |c| &c.rate_limits);
+        let (limiter, direction) = match direction {
+            "up" | "upload" => (&limits.upload, "upload"),
+            "down" | "download" => (&limits.download, "download"),
+            _ => return self.display_error(&format!("Expected up or down, not {direction}"))
+        };
+        limiter.set_rate(rate);
+        let mut message = Line::from(Span::styled(format!(
+            "Set {direction} rate to {}", limiter.display()
+        ), INFO));
+        if let Some(c) = connection {
+            message.push_span(Span::styled(" for ", INFO));
+            message.spans.extend(c.display_peer(false).spans);
+        }
+
+        self.display_msg(&message)
+    }
+
+    ///Shows the short authentication string and fingerprint to compare with the peer
+    fn display_verification(&mut self, connection: &Connection) -> Result<()> {
+        let mut message = Line::from(Span::styled("Compare these with ", INFO));
+        message.spans.extend(connection.display_peer(false).spans);
+        message.push_span(Span::styled(" over a channel you trust, like a phone call:", INFO));
+        self.display_msg(&message)?;
+        let emoji: Vec<_> = connection.sas.iter().map(|(e, name)| format!("{e} {name}")).collect();
+        self.display_msg(&Line::from(Span::styled(format!("  {}", emoji.join("  ")), COMMAND)))?;
+        self.display_msg(&Line::from(Span::styled(format!(
+            "Your fingerprint is {}, theirs is {}",
+            short_fingerprint(&fingerprint(&self.identity.public_key())),
+            short_fingerprint(&connection.fingerprint)
+        ), INFO)))?;
+        self.display_msg(&Line::from(Span::styled(format!(
+            "If everything matches, run /trust {}",
+            connection.peer_nick.read().unwrap().as_deref().unwrap_or(&connection.peer_addr)
+        ), INFO)))
+    }
+
     fn connect(&mut self, addr: &str) -> Result<()> {
         if self.get_connection(addr).is_some() {
             return self.display_error(&format!("Already connected to {addr}"));
         }
+        if self.pending_connections.iter().any(|p| p.addr == addr) {
+            return self.display_error(&format!("Already connecting to {addr}"));
+        }
         self.display_msg(&Line::from(Span::styled(format!("Connecting to {}...", addr), INFO)))?;
-        let a = addr.to_string();
-        let t = self.tx.clone();
-        self.handles.push(spawn(move || -> Result<()> {
-            let sleep_secs = 5u64;
-            for n in 0..CONNECTION_RETRIES {
-                if n > 0 {
-                    t.send(ErrorEvent(format!(
-                        "Failed to connect to {a}, retrying in {sleep_secs} seconds..."
-                    )))?;
-                    sleep(Duration::from_secs(sleep_secs));
-                }
-                if let Ok(s) = TcpStream::connect(&a) {
-                    return Ok(t.send(NewStream(s))?);
-                }
-            }
-            t.send(ErrorEvent(format!("Failed to connect to {a}")))?;
-
-            Ok(())
-        }));
+        self.start_connecting(addr.to_string(), None, Some(CONNECTION_RETRIES));
 
         Ok(())
     }
 
-    fn disconnect(&mut self, peer_addr: &str, self_initiated: bool) -> Result<()> {
+    ///Starts connecting to `addr` in the background, see [`PendingConnection`]
+    fn start_connecting(
+        &mut self,
+        addr: String,
+        peer: Option<(String, Option<String>)>,
+        max_attempts: Option<u32>
+    ) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (t, r, a, c) = (self.tx.clone(), self.running.clone(), addr.clone(), cancelled.clone());
+        self.handles.push(spawn(move || connect_with_retries(t, r, a, c, max_attempts)));
+        self.pending_connections.push(PendingConnection {
+            addr,
+            peer,
+            attempts: 0,
+            next_attempt: None,
+            cancelled
+        });
+    }
+
+    ///Returns the index in `pending_connections` of the one to `peer`, a nick or address
+    fn find_pending_connection(&self, peer: &str) -> Option<usize> {
+        self.pending_connections.iter().position(|p| {
+            p.addr == peer || p.peer.as_ref().is_some_and(|(_, n)| n.as_deref() == Some(peer))
+        })
+    }
+
+    ///Removes the connection to `peer_addr` and its transfers,
+    ///if it's `self_initiated` the peer is told why with [`send_goodbye`] and not reconnected to,
+    ///otherwise it's reconnected to unless it said goodbye
+    fn disconnect(
+        &mut self,
+        peer_addr: &str,
+        self_initiated: bool,
+        reason: Option<&str>
+    ) -> Result<()> {
         let mut disconnected = false;
         let mut message = Line::raw("");
+        let mut reconnect = None;
+        let mut goodbye = None;
+        self.goodbyes.retain(|h| !h.is_finished());
         self.connections.retain(|c| {
             if c.peer_addr == peer_addr {
-                let _ = c.stream.shutdown(Shutdown::Both);
+                if self_initiated {
+                    //shuts down the stream either way, without blocking the UI
+                    let (c, r) = (c.clone(), reason.map(str::to_string));
+                    self.goodbyes.push(spawn(move || send_goodbye(c, r)));
+                } else {
+                    let _ = c.stream.shutdown(Shutdown::Both);
+                }
                 message = c.display_peer(false);
                 disconnected = true;
+                goodbye = c.goodbye.lock().unwrap().take();
+                let peer = (c.fingerprint.clone(), c.peer_nick.read().unwrap().clone());
+                reconnect = c.reconnect_addr().map(|a| (a, peer));
 
                 false
             } else {
@@ -567,6 +1616,25 @@ impl App<'static> {
             }
         });
 
+        let mut interrupted = vec![];
+        self.downloads.retain(|d| {
+            let keep = d.connection.peer_addr != peer_addr;
+            if !keep && d.state != TransferState::Pending {
+                interrupted.push(format!(
+                    "Download of \"{}\" interrupted at {}, it resumes if the peer sends it again",
+                    d.path, d.progress
+                ));
+            }
+            keep
+        });
+        self.uploads.retain(|u| {
+            let keep = u.connection.peer_addr != peer_addr;
+            if !keep && u.state != TransferState::Pending {
+                self.interrupted_uploads.push((u.connection.fingerprint.clone(), u.path.clone()));
+            }
+            keep
+        });
+
         if self_initiated {
             if disconnected {
                 let mut msg = Line::from(Span::styled("Disconnected ", INFO));
@@ -577,33 +1645,166 @@ impl App<'static> {
                     "Failed to disconnect from {peer_addr}; no such peer"
                 ))?;
             }
-        } else {
-            if disconnected {
-                message.push_span(Span::styled(" disconnected", INFO));
-                self.display_msg(&message)?;
+        } else if disconnected && let Some(reason) = goodbye {
+            message.push_span(Span::styled(" left", INFO));
+            if !reason.is_empty() {
+                message.push_span(format!(" ({reason})"));
+            }
+            self.display_msg(&message)?;
+        } else if disconnected {
+            message.push_span(Span::styled(" disconnected", INFO));
+            if let Some((addr, peer)) = reconnect
+                && !self.pending_connections.iter().any(|p| p.addr == addr) {
+                message.push_span(Span::styled(format!(", reconnecting to {addr}"), INFO));
+                self.start_connecting(addr, Some(peer), None);
             }
+            self.display_msg(&message)?;
+        }
+        for msg in interrupted {
+            self.display_msg(&Line::from(Span::styled(msg, INFO)))?;
         }
 
         Ok(())
     }
 
+    ///Says goodbye to every peer with an optional `reason`, then stops the app
+    fn quit(&mut self, reason: Option<&str>) -> Result<()> {
+        let addrs: Vec<String> = self.connections.iter().map(|c| c.peer_addr.clone()).collect();
+        for addr in &addrs {
+            self.disconnect(addr, true, reason)?;
+        }
+        //each one gives up after a short timeout
+        for handle in self.goodbyes.drain(..) {
+            let _ = handle.join();
+        }
+        self.running.store(false, Ordering::Relaxed);
+
+        Ok(())
+    }
+
     fn broadcast_input_msg(&mut self, msg_type: &MessageType) {
         let msg = Arc::new(self.input_buf.0.clone().into_iter().collect::<String>());
         for c in &self.connections {
             let c = c.clone();
             let m = msg.clone();
-            let t = msg_type.clone();
+            let t = *msg_type;
             self.handles.push(spawn(move || -> Result<()> { send_msg(c, m, &t) }));
         }
     }
 
-    fn broadcast_file(&mut self, path: &Path) {
-        let path = Arc::new(path.to_path_buf());
-        for c in &self.connections {
-            let c = c.clone();
-            let p = path.clone();
-            self.handles.push(spawn(move || -> Result<()> { send_file(c, p) }));
+    ///Offers the image at `path` to all peers with a preview of it,
+    ///and shows the preview
+    fn broadcast_image(&mut self, path: &Path) -> Result<()> {
+        let preview = match ImagePreview::load(path) {
+            Ok(p) => Arc::new(p),
+            Err(e) => return self.display_error(&format!(
+                "Failed to load image \"{}\": {e}", path.display()
+            ))
+        };
+        for c in self.connections.clone() {
+            self.offer_file(c, path, Some(preview.clone()))?;
+        }
+        self.display_msg(&Line::from(Span::styled(format!(
+            "Sent image \"{}\" ({}x{}), Ctrl+O to view",
+            preview.name, preview.width, preview.height
+        ), INFO)))?;
+        self.display_image(InlineImage {
+            source: None,
+            preview: Arc::unwrap_or_clone(preview),
+            path: Some(path.to_path_buf())
+        });
+
+        Ok(())
+    }
+
+    ///Shows the thumbnail of `image` under the last message, without logging it
+    fn display_image(&mut self, image: InlineImage) {
+        let lines = half_blocks(&image.preview.thumbnail);
+        if self.scroll_pos.get() > 0 {
+            self.scroll_pos.set(self.scroll_pos.get() + lines.len());
+        }
+        self.messages.extend(lines);
+        self.images.push(image);
+    }
+
+    ///Returns the instances found on the local network that sent a beacon recently
+    fn discovered_peers(&self) -> Vec<&DiscoveredPeer> {
+        self.discovered.iter().filter(|d| d.last_seen.elapsed() < BEACON_EXPIRY).collect()
+    }
+
+    ///Opens the image viewer on the image at `index` in `images`
+    fn open_viewer(&mut self, index: usize) {
+        let image = &self.images[index];
+        //shrink it once to what the terminal can show, so redrawing it stays cheap
+        let (width, height) = self.terminal_size;
+        let full = image.path.as_ref()
+            .and_then(|p| image::open(p).ok())
+            .map(|i| i.thumbnail(width as u32, height as u32 * 2).to_rgb8());
+        self.viewer = Some(ImageViewer { index, image: full });
+    }
+
+    fn broadcast_file(&mut self, path: &Path) -> Result<()> {
+        for c in self.connections.clone() {
+            self.offer_file(c, path, None)?;
         }
+
+        Ok(())
+    }
+
+    ///Offers the file or directory at `path` to `connection`, after `image` if it's one,
+    ///and keeps it as an [`Upload`] until answered
+    fn offer_file(
+        &mut self,
+        connection: Arc<Connection>,
+        path: &Path,
+        image: Option<Arc<ImagePreview>>
+    ) -> Result<()> {
+        //paths like `.` only get a name to offer once resolved
+        let path = match path.canonicalize() {
+            Ok(p) => p,
+            Err(e) => return self.display_error(&format!(
+                "Failed to read \"{}\": {e}", path.display()
+            ))
+        };
+        if path.file_name().is_none() {
+            return self.display_error(&format!(
+                "Failed to offer \"{}\", it has no name", path.display()
+            ));
+        }
+        let entries = match read_tree(&path) {
+            Ok(e) => e,
+            Err(e) => return self.display_error(&format!(
+                "Failed to read \"{}\": {e}", path.display()
+            ))
+        };
+        let offer = match Offer::new(next_transfer_id(), &path, entries) {
+            Ok(o) => o,
+            Err(e) => return self.display_error(&format!(
+                "Failed to offer \"{}\": {e}", path.display()
+            ))
+        };
+        let upload = Upload {
+            connection,
+            id: offer.id,
+            path: Arc::new(path),
+            size: offer.size,
+            entries: Arc::new(offer.entries.clone()),
+            progress: Size::from_bytes(0),
+            speed: TransferSpeed::new(0),
+            state: TransferState::Pending,
+            control: Arc::new(UploadControl::default())
+        };
+        let c = upload.connection.clone();
+        self.handles.push(spawn(move || -> Result<()> {
+            //the preview goes first so the peer can see it before answering the offer
+            if let Some(i) = image {
+                send_image(&c, &i, offer.id)?;
+            }
+            send_offer(c, &offer)
+        }));
+        self.uploads.push(upload);
+
+        Ok(())
     }
 
     ///Adds a message to the list of messages with the current time appended to the front,
@@ -663,14 +1864,8 @@ impl App<'static> {
         ]))
     }
 
-    fn find_peer_addr(&self, peer_nick: &str) -> Option<String> {
-        if let Some(c) = self.connections.iter().find(
-            |c| *c.peer_nick.read().unwrap() == Some(peer_nick.to_string())
-        ) {
-            Some(c.peer_addr.clone())
-        } else {
-            None
-        }
+    fn find_peer_addr(&self, peer: &str) -> Option<String> {
+        self.connections.iter().find(|c| c.matches(peer)).map(|c| c.peer_addr.clone())
     }
 
     fn get_connection(&self, peer_addr: &str) -> Option<Arc<Connection>> {
@@ -700,6 +1895,19 @@ impl Widget for &App<'static> {
             for c in &self.connections {
                 peers.push(c.display_peer(true))
             }
+            for p in &self.pending_connections {
+                let name = match &p.peer {
+                    Some((_, Some(nick))) => format!("<{nick}>"),
+                    _ => p.addr.clone()
+                };
+                let status = match p.next_attempt {
+                    Some(t) => format!(
+                        "retrying in {}", format_duration(t.saturating_duration_since(Instant::now()))
+                    ),
+                    None => "connecting...".to_string()
+                };
+                peers.push(Line::from(Span::styled(format!("{name} {status}"), INFO)));
+            }
             let peer_paragraph = Paragraph::new(peers).block(
                 Block::bordered().title("─┤Peers├").merge_borders(Fuzzy).padding(
                     Padding::horizontal(1)
@@ -708,27 +1916,52 @@ impl Widget for &App<'static> {
             peer_paragraph.render(peer_area, buf);
         }
 
-        if !self.downloads.is_empty() {
-            let mut downloads: Vec<_> = self.downloads.iter().map(|d| {
-                let mut msg = d.connection.display_peer(false);
-                msg.push_span(format!(" \"{}\": {}/{}", d.path, d.progress, d.size));
-                msg
-            }).collect();
-            downloads = wrap_lines(downloads, message_area.width as usize - 4);
+        let downloads: Vec<_> = self.downloads.iter().map(|d| {
+            let mut msg = d.connection.display_peer(false);
+            msg.push_span(format!(" [{}] \"{}\": ", d.id, d.path));
+            match d.state {
+                TransferState::Pending => msg.push_span(format!("{}, awaiting /accept", d.size)),
+                TransferState::Active => msg.push_span(format!(
+                    "{}/{}{}", d.progress, d.size, d.speed.display(d.size.bytes() as u64)
+                )),
+                TransferState::Paused => msg.push_span(format!(
+                    "{}/{}, paused", d.progress, d.size
+                ))
+            }
+            msg
+        }).collect();
+        let uploads: Vec<_> = self.uploads.iter().map(|u| {
+            let mut msg = u.connection.display_peer(false);
+            let size = Size::from_bytes(u.size);
+            msg.push_span(format!(" [{}] \"{}\": ", u.id, u.path.display()));
+            match u.state {
+                TransferState::Pending => msg.push_span(format!("{size}, awaiting answer")),
+                TransferState::Active => msg.push_span(format!(
+                    "{}/{size}{}", u.progress, u.speed.display(u.size)
+                )),
+                TransferState::Paused => msg.push_span(format!("{}/{size}, paused", u.progress))
+            }
+            msg
+        }).collect();
+        for (title, rows) in [("─┤Downloads├", downloads), ("─┤Uploads├", uploads)] {
+            if rows.is_empty() {
+                continue;
+            }
+            let rows = wrap_lines(rows, message_area.width as usize - 4);
             let vertical_layout = Layout::vertical([
-                Constraint::Min(downloads.len() as u16 + 1),
+                Constraint::Min(rows.len() as u16 + 1),
                 Constraint::Percentage(100),
             ]).split(message_area);
-            let mut download_area = vertical_layout[0];
-            download_area.height += 1;
+            let mut panel_area = vertical_layout[0];
+            panel_area.height += 1;
             message_area = vertical_layout[1];
 
-            let download_paragraph = Paragraph::new(downloads).block(
-                Block::bordered().title("─┤Downloads├").merge_borders(Fuzzy).padding(
+            let panel_paragraph = Paragraph::new(rows).block(
+                Block::bordered().title(title).merge_borders(Fuzzy).padding(
                     Padding::horizontal(1)
                 )
             );
-            download_paragraph.render(download_area, buf);
+            panel_paragraph.render(panel_area, buf);
         }
 
         //the -2 is to account for the border
@@ -780,10 +2013,10 @@ impl Widget for &App<'static> {
         let (second, third) = second.split_at(1);
 
         let input = Paragraph::new(Line::from(vec![
-            Span::raw(first.into_iter().collect::<String>()),
+            Span::raw(first.iter().collect::<String>()),
             //blinking doesn't work on certain terminals
-            Span::styled(second.into_iter().collect::<String>(), Style::new().underlined().slow_blink()),
-            Span::raw(third.into_iter().collect::<String>()),
+            Span::styled(second.iter().collect::<String>(), Style::new().underlined().slow_blink()),
+            Span::raw(third.iter().collect::<String>()),
         ])).block(Block::bordered().merge_borders(Fuzzy).padding(Padding::horizontal(1)));
 
         if scrolling {
@@ -803,6 +2036,111 @@ impl Widget for &App<'static> {
         message_paragraph.render(message_area, buf);
         nick.render(nick_area, buf);
         input.render(input_area, buf);
+
+        if self.show_discovered {
+            render_discovered(&self.discovered_peers(), &self.connections, area, buf);
+        }
+        if let Some(viewer) = &self.viewer {
+            render_viewer(self.images.get(viewer.index), viewer, area, buf);
+        }
+    }
+}
+
+///Draws the instances found on the local network over the middle of `area`,
+///numbered for connecting to them with one key
+fn render_discovered(
+    peers: &[&DiscoveredPeer],
+    connections: &Connections,
+    area: Rect,
+    buf: &mut Buffer
+) {
+    let mut lines: Vec<Line> = peers.iter().take(9).enumerate().map(|(i, d)| {
+        let mut line = Line::from(vec![
+            Span::styled(format!("{} ", i + 1), COMMAND),
+            Span::raw(format!("<{}> ", d.nick.as_deref().unwrap_or("?"))),
+            Span::raw(d.addr.clone()),
+            Span::styled(format!(" {}", short_fingerprint(&d.fingerprint)), INFO),
+        ]);
+        if connections.iter().any(|c| c.fingerprint == d.fingerprint) {
+            line.push_span(Span::styled(", connected", INFO));
+        }
+        line
+    }).collect();
+    if lines.is_empty() {
+        lines.push(Line::from(Span::styled("Looking for instances on the local network...", INFO)));
+    }
+    let [area] = Layout::vertical([Constraint::Length(lines.len() as u16 + 2)])
+        .flex(Flex::Center)
+        .areas(area.inner(Margin::new(area.width / 8, 0)));
+    let block = Block::bordered()
+        .title("─┤Discovered, 1-9 to connect, Esc to close├")
+        .merge_borders(Fuzzy)
+        .padding(Padding::horizontal(1));
+    Clear.render(area, buf);
+    Paragraph::new(lines).block(block).render(area, buf);
+}
+
+///Draws the image viewer over the middle of `area`
+fn render_viewer(image: Option<&InlineImage>, viewer: &ImageViewer, area: Rect, buf: &mut Buffer) {
+    let Some(image) = image else {
+        return;
+    };
+    let area = area.inner(Margin::new(area.width / 20, area.height / 20));
+    let preview = &image.preview;
+    let mut title = format!("─┤{} ({}x{})", preview.name, preview.width, preview.height);
+    if viewer.image.is_none() {
+        title.push_str(", preview");
+    }
+    title.push('├');
+    let block = Block::bordered().title(title).merge_borders(Fuzzy);
+    let inner = block.inner(area);
+    Clear.render(area, buf);
+    block.render(area, buf);
+
+    //scale to fit, each line is two pixels tall
+    let source = viewer.image.as_ref().unwrap_or(&preview.thumbnail);
+    let scale = f64::min(
+        inner.width as f64 / source.width().max(1) as f64,
+        inner.height as f64 * 2.0 / source.height().max(1) as f64
+    );
+    let width = ((source.width() as f64 * scale) as u32).max(1);
+    let height = ((source.height() as f64 * scale) as u32).max(1);
+    let scaled = image::imageops::resize(source, width, height, FilterType::Triangle);
+    let lines = half_blocks(&scaled);
+    let [image_area] = Layout::horizontal([Constraint::Length(width as u16)])
+        .flex(Flex::Center)
+        .areas(inner);
+    let [image_area] = Layout::vertical([Constraint::Length(lines.len() as u16)])
+        .flex(Flex::Center)
+        .areas(image_area);
+    Paragraph::new(lines).render(image_area, buf);
+}
+
+///Draws `image` as lines of upper half blocks,
+///colored with the top pixel of each pair in front and the bottom one behind
+fn half_blocks(image: &RgbImage) -> Vec<Line<'static>> {
+    (0..image.height()).step_by(2).map(|y| {
+        Line::from((0..image.width()).map(|x| {
+            let [r, g, b] = image.get_pixel(x, y).0;
+            let mut style = Style::new().fg(Color::Rgb(r, g, b));
+            if y + 1 < image.height() {
+                let [r, g, b] = image.get_pixel(x, y + 1).0;
+                style = style.bg(Color::Rgb(r, g, b));
+            }
+            Span::styled("▀", style)
+        }).collect::<Vec<_>>())
+    }).collect()
+}
+
+///Formats `duration` as hours and minutes, minutes and seconds, or just seconds
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
     }
 }
 
@@ -817,6 +2155,11 @@ fn wrap_lines(lines: Vec<Line>, area_width: usize) -> Vec<Line> {
     let mut output = vec![];
     let mut line;
     for l in lines {
+        //lines that already fit are kept as they are, which keeps images made of blocks intact
+        if l.width() <= area_width {
+            output.push(l);
+            continue;
+        }
         let mut line_width = 0;
         line = Line::default();
         for span in l {
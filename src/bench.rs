@@ -0,0 +1,122 @@
+//!Harness for the benchmarks in `benches`, not part of the app,
+//!only built with the `bench` feature: `cargo bench --features bench`
+
+use crate::app::AppEvent::{self, *};
+use crate::config::Config;
+use crate::connections::*;
+use crate::identity::Identity;
+use crate::ratelimit::RateLimits;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use std::fs;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::spawn;
+use std::time::Duration;
+
+///Time to wait for an event before giving up on a transfer
+const EVENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+///Two peers connected over loopback, sending files to each other without the app
+pub struct Loopback {
+    sender: Arc<Connection>,
+    sender_tx: Sender<AppEvent>,
+    sender_events: Receiver<AppEvent>,
+    receiver_events: Receiver<AppEvent>,
+    running: Arc<AtomicBool>
+}
+
+impl Loopback {
+    ///Connects two peers that keep their identities and downloads in `dir`
+    pub fn new(dir: &Path) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let sender_stream = TcpStream::connect(listener.local_addr()?)?;
+        let (receiver_stream, _) = listener.accept()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let (sender_tx, sender_events) = mpsc::channel();
+        let (receiver_tx, receiver_events) = mpsc::channel();
+        let peers = [
+            ("sender", sender_tx.clone(), sender_stream, true),
+            ("receiver", receiver_tx, receiver_stream, false)
+        ];
+        for (name, tx, stream, outgoing) in peers {
+            let config = Config {
+                download_dir: dir.join(name),
+                identity_path: dir.join(format!("{name}.key")),
+                ..Config::default()
+            };
+            fs::create_dir_all(&config.download_dir)?;
+            let identity = Arc::new(Identity::load_or_generate(&config.identity_path)?);
+            let r = running.clone();
+            let c = Arc::new(RwLock::new(config));
+            let l = Arc::new(RwLock::new(vec![]));
+            let rl = Arc::new(RateLimits::new(None, None));
+            spawn(move || connection_handler(tx, r, c, l, identity, rl, stream, outgoing));
+        }
+        let sender = wait_for(&sender_events, |e| match e {
+            ConnectionEvent(c) => Some(c),
+            _ => None
+        })?;
+        wait_for(&receiver_events, |e| matches!(e, ConnectionEvent(_)).then_some(()))?;
+
+        Ok(Self { sender, sender_tx, sender_events, receiver_events, running })
+    }
+
+    ///Sends the file or directory at `path`, waits until it's received, then removes the copy
+    pub fn transfer(&self, path: &Path) -> Result<()> {
+        let path = Arc::new(path.to_path_buf());
+        let offer = Offer::new(next_transfer_id(), &path, read_tree(&path)?)?;
+        let (id, entries) = (offer.id, Arc::new(offer.entries.clone()));
+        send_offer(self.sender.clone(), &offer)?;
+        let download = wait_for(&self.receiver_events, |e| match e {
+            OfferEvent(d) => Some(d),
+            _ => None
+        })?;
+        let offset = {
+            let mut offers = download.connection.offers.lock().unwrap();
+            let offer = offers.get_mut(&download.transfer_id)
+                .ok_or_else(|| eyre!("Offer disappeared before it was accepted"))?;
+            offer.accepted = true;
+            offer.offset()
+        };
+        send_answer(download.connection, download.transfer_id, Some(offset))?;
+        wait_for(&self.sender_events, |e| matches!(e, OfferAnswerEvent(..)).then_some(()))?;
+
+        let (c, t) = (self.sender.clone(), self.sender_tx.clone());
+        send_file(t, c, path, entries, id, offset, Arc::default())?;
+        let received = wait_for(&self.receiver_events, |e| match e {
+            FileReceivedEvent(_, _, p) => Some(p),
+            _ => None
+        })?;
+        if received.is_dir() {
+            fs::remove_dir_all(received)?;
+        } else {
+            fs::remove_file(received)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.sender.stream.shutdown(Shutdown::Both);
+    }
+}
+
+///Returns the first event in `events` that `f` returns something for,
+///or an error if one is reported first
+fn wait_for<T>(events: &Receiver<AppEvent>, mut f: impl FnMut(AppEvent) -> Option<T>) -> Result<T> {
+    loop {
+        match events.recv_timeout(EVENT_TIMEOUT)? {
+            ErrorEvent(e) => return Err(eyre!(e)),
+            e => if let Some(t) = f(e) {
+                return Ok(t);
+            }
+        }
+    }
+}
@@ -1,55 +1,132 @@
 use clap::Parser;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use pnet::ipnetwork::IpNetwork;
 use serde::Deserialize;
+use std::env;
 use std::env::home_dir;
 use std::fs;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use toml_edit::{Array, DocumentMut};
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
-pub(crate) struct Config {
+pub struct Config {
+    ///IPs, CIDR ranges or fingerprints allowed to connect, anyone not denied if empty
+    pub(crate) allow: Vec<String>,
+    ///IPs, CIDR ranges or fingerprints of peers whose files are accepted without asking,
+    ///never nicks since any peer can pick any nick
+    pub(crate) auto_accept_peers: Vec<String>,
+    ///Files up to this size in bytes are accepted without asking
+    pub(crate) auto_accept_size: Option<u64>,
     pub(crate) debug: bool,
+    ///IPs, CIDR ranges or fingerprints refused before the handshake or once identified
+    pub(crate) deny: Vec<String>,
+    ///Whether to announce this instance on the local network and find others for `/discover`
+    pub(crate) discovery: bool,
+    ///Directory received files are saved to
+    pub(crate) download_dir: PathBuf,
+    ///File containing the long-term identity key, generated if it doesn't exist
+    pub(crate) identity_path: PathBuf,
+    ///File recording the identity fingerprint of each peer seen so far
+    pub(crate) known_peers_path: PathBuf,
+    ///IPs to listen on, `all` for every local address, `::` for one listener taking
+    ///both IPv4 and IPv6, link-local IPv6 addresses need a scope id like `fe80::1%eth0`
     pub(crate) listen_ips: Vec<String>,
     pub(crate) listen_ports: Vec<u16>,
     pub(crate) log_messages: bool,
     pub(crate) log_path: PathBuf,
+    ///Maximum size in bytes of a single text or command frame from a peer
+    pub(crate) max_message_size: u64,
+    ///Maximum size in bytes of a file a peer may send
+    pub(crate) max_file_size: u64,
+    ///Largest piece in bytes files are sent in, pieces grow up to it on fast connections,
+    ///at least 64 KiB and at most 16 MiB
+    pub(crate) max_piece_size: u64,
+    ///Bytes per second files are sent at to all peers together, unlimited if not set
+    pub(crate) max_upload_rate: Option<u64>,
+    ///Bytes per second files are received at from all peers together, unlimited if not set
+    pub(crate) max_download_rate: Option<u64>,
     pub(crate) nick: Option<String>,
+    ///Rate limits for single peers, on top of the ones for all peers
+    pub(crate) peer_rate_limits: Vec<PeerRateLimit>,
+    ///Passphrase mixed into the key exchange, only peers using the same one can connect
+    pub(crate) psk: Option<String>,
+    ///Messages sent with a key before it's replaced, 0 to disable
+    pub(crate) rekey_messages: u64,
+    ///Bytes sent with a key before it's replaced, 0 to disable
+    pub(crate) rekey_bytes: u64,
+    ///Minutes a key is used before it's replaced, 0 to disable
+    pub(crate) rekey_minutes: u64,
+    ///Addresses to connect to on startup, IPv6 ones in brackets like `[::1]:4000`
     pub(crate) startup_connections: Vec<String>,
+    ///Config file that changes made at runtime are saved to, none with `--no-config`
+    #[serde(skip)]
+    pub(crate) path: Option<PathBuf>,
+    ///[`Config::allow`] and [`Config::deny`] as in the config file, without `--allow` or `--deny`,
+    ///changed along with them and saved by [`Config::save_rules`]
+    #[serde(skip)]
+    pub(crate) file_rules: (Vec<String>, Vec<String>)
 }
 
 impl Config {
-    pub(crate) fn parse() -> Self {
+    pub fn parse() -> Self {
         let mut config = Self::default();
         let args = Args::parse();
         let mut file_config = None;
+        let mut config_path = None;
         if !args.no_config {
             if let Some(path) = args.config_path {
                 file_config = read_config_file(&path);
+                config_path = Some(path);
             } else {
                 let mut config_paths = vec![PathBuf::from("tcp_messenger.toml")];
-                if let Some(dir) = home_dir() {
-                    #[cfg(target_family = "unix")]
-                    config_paths.push(PathBuf::from(dir).join(".config/tcp_messenger/config.toml"));
-                    #[cfg(target_family = "windows")]
-                    config_paths.push(
-                        PathBuf::from(dir).join("AppData\\Roaming\\tcp_messenger\\config.toml")
-                    );
+                if let Some(dir) = config_dir() {
+                    config_paths.push(dir.join("config.toml"));
                 }
-                for path in config_paths {
-                    if let Some(a) = read_config_file(&path) {
+                for path in &config_paths {
+                    if let Some(a) = read_config_file(path) {
                         file_config = Some(a);
+                        config_path = Some(path.clone());
                         break;
                     }
                 }
+                //save to the config directory if there isn't a config file yet
+                config_path = config_path.or_else(|| config_paths.pop());
             }
         }
         if let Some(cfg) = file_config {
             config = cfg;
         }
+        config.path = config_path;
+        config.file_rules = (config.allow.clone(), config.deny.clone());
 
         //would be nice to have a function to do this instead
+        if let Some(a) = args.allow {
+            config.allow = a;
+        }
+        if let Some(a) = args.auto_accept_peers {
+            config.auto_accept_peers = a;
+        }
+        if let Some(a) = args.auto_accept_size {
+            config.auto_accept_size = Some(a);
+        }
         if args.debug {
             config.debug = args.debug;
         }
+        if let Some(a) = args.deny {
+            config.deny = a;
+        }
+        if let Some(a) = args.download_dir {
+            config.download_dir = a;
+        }
+        if let Some(a) = args.identity_path {
+            config.identity_path = a;
+        }
+        if let Some(a) = args.known_peers_path {
+            config.known_peers_path = a;
+        }
         if let Some(a) = args.listen_ips {
             config.listen_ips = a;
         }
@@ -59,30 +136,279 @@ impl Config {
         if args.log_messages {
             config.log_messages = args.log_messages;
         }
+        if args.discovery {
+            config.discovery = args.discovery;
+        }
         if let Some(a) = args.log_path {
             config.log_path = a;
         }
+        if let Some(a) = args.max_message_size {
+            config.max_message_size = a;
+        }
+        if let Some(a) = args.max_file_size {
+            config.max_file_size = a;
+        }
+        if let Some(a) = args.max_piece_size {
+            config.max_piece_size = a;
+        }
+        if let Some(a) = args.max_upload_rate {
+            config.max_upload_rate = Some(a);
+        }
+        if let Some(a) = args.max_download_rate {
+            config.max_download_rate = Some(a);
+        }
         if let Some(a) = args.nick {
             config.nick = Some(a);
         }
+        if let Some(a) = args.psk {
+            config.psk = Some(a);
+        }
+        if let Some(a) = args.rekey_messages {
+            config.rekey_messages = a;
+        }
+        if let Some(a) = args.rekey_bytes {
+            config.rekey_bytes = a;
+        }
+        if let Some(a) = args.rekey_minutes {
+            config.rekey_minutes = a;
+        }
         if let Some(a) = args.startup_connections {
             config.startup_connections = a;
         }
+        for rule in config.allow.iter().chain(&config.deny) {
+            if PeerRule::parse(rule).is_none() {
+                eprintln!("Ignoring invalid allow/deny rule: {rule}");
+            }
+        }
+        for peer in &config.auto_accept_peers {
+            if PeerRule::parse(peer).is_none() {
+                eprintln!("Ignoring invalid auto-accept peer: {peer}");
+            }
+        }
+        for limit in &config.peer_rate_limits {
+            if PeerRule::parse(&limit.peer).is_none() {
+                eprintln!("Ignoring rate limit for invalid peer: {}", limit.peer);
+            }
+        }
 
         config
     }
+
+    ///Returns whether a peer at `ip` with `fingerprint` may connect according to
+    ///[`Config::allow`] and [`Config::deny`]
+    ///
+    ///Without a fingerprint, peers that could still be allowed by fingerprint are let through,
+    ///so this has to be checked again once the peer is identified
+    pub(crate) fn is_allowed(&self, ip: IpAddr, fingerprint: Option<&str>) -> bool {
+        let ip = ip.to_canonical();
+        let matches = |r: &PeerRule| r.matches(ip, fingerprint);
+        if self.deny.iter().filter_map(|r| PeerRule::parse(r)).any(|r| matches(&r)) {
+            return false;
+        }
+        let allow: Vec<_> = self.allow.iter().filter_map(|r| PeerRule::parse(r)).collect();
+
+        allow.is_empty() || allow.iter().any(matches) || (
+            fingerprint.is_none() && allow.iter().any(|r| matches!(r, PeerRule::Fingerprint(_)))
+        )
+    }
+
+    ///Returns the `(upload, download)` rates for a peer at `ip` with `fingerprint`
+    ///from the first matching entry in [`Config::peer_rate_limits`]
+    pub(crate) fn peer_rate_limit(
+        &self,
+        ip: IpAddr,
+        fingerprint: &str
+    ) -> (Option<u64>, Option<u64>) {
+        let ip = ip.to_canonical();
+        self.peer_rate_limits.iter()
+            .find(|l| PeerRule::parse(&l.peer).is_some_and(|r| r.matches(ip, Some(fingerprint))))
+            .map_or((None, None), |l| (l.max_upload_rate, l.max_download_rate))
+    }
+
+    ///Returns whether files from a peer at `ip` with `fingerprint` are accepted without asking
+    ///according to [`Config::auto_accept_peers`]
+    pub(crate) fn auto_accepts(&self, ip: IpAddr, fingerprint: &str) -> bool {
+        let ip = ip.to_canonical();
+        self.auto_accept_peers.iter()
+            .filter_map(|p| PeerRule::parse(p))
+            .any(|r| r.matches(ip, Some(fingerprint)))
+    }
+
+    ///Adds `rule` to [`Config::deny`] and removes it from [`Config::allow`],
+    ///returns false if it was already denied
+    pub(crate) fn block(&mut self, rule: &PeerRule) -> bool {
+        block(&mut self.file_rules.0, &mut self.file_rules.1, rule);
+        block(&mut self.allow, &mut self.deny, rule)
+    }
+
+    ///Removes `rule` from [`Config::deny`], returns false if it wasn't denied
+    ///
+    ///Fingerprints match if either is a prefix of the other,
+    ///so a short fingerprint unblocks the full one
+    pub(crate) fn unblock(&mut self, rule: &PeerRule) -> bool {
+        unblock(&mut self.file_rules.1, rule);
+        unblock(&mut self.deny, rule)
+    }
+
+    ///Writes the allow and deny rules from the config file, with any changes made at runtime,
+    ///back to it, leaving the rest of it and its formatting as it is
+    pub(crate) fn save_rules(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Err(eyre!("running without a config file"));
+        };
+        let mut document = if fs::exists(path)? {
+            fs::read_to_string(path)?.parse::<DocumentMut>()?
+        } else {
+            DocumentMut::new()
+        };
+        let (allow, deny) = &self.file_rules;
+        for (key, rules) in [("allow", allow), ("deny", deny)] {
+            //change existing arrays in place to keep comments after them
+            if let Some(array) = document.get_mut(key).and_then(|a| a.as_array_mut()) {
+                array.clear();
+                array.extend(rules);
+            } else {
+                document[key] = toml_edit::value(rules.iter().collect::<Array>());
+            }
+        }
+        if let Some(dir) = path.parent() && !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, document.to_string())?;
+
+        Ok(())
+    }
+}
+
+///Entry in [`Config::peer_rate_limits`]
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PeerRateLimit {
+    ///IP, CIDR range or fingerprint of the peers it applies to, see [`PeerRule::parse`]
+    pub(crate) peer: String,
+    ///Bytes per second, unlimited if not set
+    pub(crate) max_upload_rate: Option<u64>,
+    pub(crate) max_download_rate: Option<u64>
+}
+
+///Adds `rule` to `deny` and removes it from `allow`, returns false if it was already denied
+fn block(allow: &mut Vec<String>, deny: &mut Vec<String>, rule: &PeerRule) -> bool {
+    allow.retain(|r| PeerRule::parse(r).as_ref() != Some(rule));
+    if deny.iter().any(|r| PeerRule::parse(r).as_ref() == Some(rule)) {
+        return false;
+    }
+    deny.push(rule.to_string());
+
+    true
+}
+
+///Removes `rule` from `deny`, returns false if it wasn't denied, see [`Config::unblock`]
+fn unblock(deny: &mut Vec<String>, rule: &PeerRule) -> bool {
+    let len = deny.len();
+    deny.retain(|r| match (PeerRule::parse(r), rule) {
+        (Some(PeerRule::Fingerprint(a)), PeerRule::Fingerprint(b)) => {
+            !a.starts_with(b.as_str()) && !b.starts_with(a.as_str())
+        }
+        (r, rule) => r.as_ref() != Some(rule)
+    });
+
+    deny.len() != len
+}
+
+///Entry in [`Config::allow`] or [`Config::deny`]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PeerRule {
+    ///Single IP or CIDR range
+    Network(IpNetwork),
+    ///Full identity fingerprint or a prefix of it, like a short fingerprint
+    Fingerprint(String)
+}
+
+impl PeerRule {
+    ///Parses an IP, a CIDR range like `192.168.1.0/24`,
+    ///or a fingerprint of at least 32 hex digits, optionally separated by `:`
+    ///
+    ///A short fingerprint written with `:` looks like a fully written out IPv6 address
+    ///and is read as one, so it has to start with `fp:` to be read as a fingerprint
+    pub(crate) fn parse(rule: &str) -> Option<Self> {
+        let rule = rule.trim();
+        if let Some(fingerprint) = rule.strip_prefix("fp:") {
+            return parse_fingerprint(fingerprint).map(Self::Fingerprint);
+        }
+        if let Ok(ip) = rule.parse::<IpAddr>() {
+            return Some(Self::Network(IpNetwork::from(ip.to_canonical())));
+        }
+        if let Ok(network) = rule.parse::<IpNetwork>() {
+            return Some(Self::Network(network));
+        }
+
+        parse_fingerprint(rule).map(Self::Fingerprint)
+    }
+
+    fn matches(&self, ip: IpAddr, fingerprint: Option<&str>) -> bool {
+        match self {
+            Self::Network(network) => network.contains(ip),
+            Self::Fingerprint(f) => fingerprint.is_some_and(|fp| fp.starts_with(f.as_str()))
+        }
+    }
+}
+
+///Returns a fingerprint of 32 to 64 hex digits without any `:` in lowercase
+fn parse_fingerprint(fingerprint: &str) -> Option<String> {
+    let hex = fingerprint.replace(':', "").to_lowercase();
+
+    ((32..=64).contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit())).then_some(hex)
+}
+
+impl std::fmt::Display for PeerRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network(n) => {
+                //single IPs are written without the prefix length
+                let host_prefix = if n.is_ipv4() { 32 } else { 128 };
+                if n.prefix() == host_prefix {
+                    write!(f, "{}", n.ip())
+                } else {
+                    write!(f, "{n}")
+                }
+            }
+            Self::Fingerprint(fp) => write!(f, "{fp}")
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            allow: vec![],
+            auto_accept_peers: vec![],
+            auto_accept_size: None,
             debug: false,
+            deny: vec![],
+            download_dir: default_download_dir(),
+            identity_path: config_dir().unwrap_or_default().join("identity.key"),
+            known_peers_path: config_dir().unwrap_or_default().join("known_peers"),
             listen_ips: vec!["all".to_string()],
             listen_ports: vec![0],
             log_messages: false,
+            discovery: false,
             log_path: PathBuf::from("messenger.log"),
+            //1MiB
+            max_message_size: 1024 * 1024,
+            //64GiB
+            max_file_size: 64 * 1024 * 1024 * 1024,
+            max_piece_size: 4 * 1024 * 1024,
+            max_upload_rate: None,
+            max_download_rate: None,
             nick: None,
-            startup_connections: vec![]
+            peer_rate_limits: vec![],
+            psk: None,
+            rekey_messages: 100_000,
+            //1GiB
+            rekey_bytes: 1024 * 1024 * 1024,
+            rekey_minutes: 10,
+            startup_connections: vec![],
+            path: None,
+            file_rules: (vec![], vec![])
         }
     }
 }
@@ -90,10 +416,24 @@ impl Default for Config {
 ///Struct for parsing command line arguments with [`clap`]
 #[derive(Parser, Debug, Clone)]
 struct Args {
+    #[arg(long, num_args = 1.., value_delimiter = ',')]
+    allow: Option<Vec<String>>,
+    #[arg(long, num_args = 1.., value_delimiter = ',')]
+    auto_accept_peers: Option<Vec<String>>,
+    #[arg(long)]
+    auto_accept_size: Option<u64>,
     #[arg(short, long)]
     config_path: Option<PathBuf>,
     #[arg(short, long, action)]
     debug: bool,
+    #[arg(long, num_args = 1.., value_delimiter = ',')]
+    deny: Option<Vec<String>>,
+    #[arg(long)]
+    download_dir: Option<PathBuf>,
+    #[arg(long)]
+    identity_path: Option<PathBuf>,
+    #[arg(long)]
+    known_peers_path: Option<PathBuf>,
     #[arg(
         short = 'i', long,
         num_args = 1..,
@@ -108,8 +448,28 @@ struct Args {
     listen_ports: Option<Vec<u16>>,
     #[arg(short, long, action)]
     log_messages: bool,
+    #[arg(long, action)]
+    discovery: bool,
     #[arg(long)]
     log_path: Option<PathBuf>,
+    #[arg(long)]
+    max_message_size: Option<u64>,
+    #[arg(long)]
+    max_file_size: Option<u64>,
+    #[arg(long)]
+    max_piece_size: Option<u64>,
+    #[arg(long)]
+    max_upload_rate: Option<u64>,
+    #[arg(long)]
+    max_download_rate: Option<u64>,
+    #[arg(long)]
+    psk: Option<String>,
+    #[arg(long)]
+    rekey_messages: Option<u64>,
+    #[arg(long)]
+    rekey_bytes: Option<u64>,
+    #[arg(long)]
+    rekey_minutes: Option<u64>,
     #[arg(short, long, num_args = 1.., value_delimiter = ',')]
     startup_connections: Option<Vec<String>>,
     #[arg(short, long)]
@@ -131,3 +491,77 @@ fn read_config_file(path: &Path) -> Option<Config> {
         None
     }
 }
+
+///Returns the directory for the config file and other persistent state
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let dir = home_dir()?;
+    #[cfg(target_family = "unix")]
+    let dir = dir.join(".config/tcp_messenger");
+    #[cfg(target_family = "windows")]
+    let dir = dir.join("AppData\\Roaming\\tcp_messenger");
+
+    Some(dir)
+}
+
+///Returns the XDG downloads directory if one is configured,
+///otherwise `Downloads` in the home directory, or the working directory as a last resort
+fn default_download_dir() -> PathBuf {
+    let Some(home) = home_dir() else {
+        return PathBuf::from(".");
+    };
+    #[cfg(target_family = "unix")]
+    {
+        if let Some(dir) = env::var_os("XDG_DOWNLOAD_DIR") {
+            return PathBuf::from(dir);
+        }
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".config"));
+        //user-dirs.dirs contains lines like XDG_DOWNLOAD_DIR="$HOME/Downloads"
+        if let Ok(dirs) = fs::read_to_string(config_home.join("user-dirs.dirs"))
+            && let Some(line) = dirs.lines().find(|l| l.starts_with("XDG_DOWNLOAD_DIR="))
+            && let Some(value) = line.split_once('=').map(|(_, v)| v.trim().trim_matches('"')) {
+            return if let Some(rest) = value.strip_prefix("$HOME") {
+                home.join(rest.trim_start_matches('/'))
+            } else {
+                PathBuf::from(value)
+            };
+        }
+    }
+
+    home.join("Downloads")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_full_ipv6_address_as_ip() {
+        let rule = PeerRule::parse("2001:0db8:0000:0000:0000:0000:0000:0001").unwrap();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(rule, PeerRule::Network(IpNetwork::from(ip)));
+    }
+
+    #[test]
+    fn parses_fingerprints() {
+        let short = "0503:7716:9f6b:043d:bccd:5027:066b:6edf";
+        let hex = short.replace(':', "");
+        assert!(matches!(PeerRule::parse(short), Some(PeerRule::Network(_))));
+        let fingerprint = Some(PeerRule::Fingerprint(hex.clone()));
+        assert_eq!(PeerRule::parse(&format!("fp:{short}")), fingerprint);
+        assert_eq!(PeerRule::parse(&hex.to_uppercase()), fingerprint);
+        assert_eq!(PeerRule::parse("fp:1234"), None);
+        //written without the prefix, so it still parses the same when saved and loaded again
+        assert_eq!(PeerRule::Fingerprint(hex.clone()).to_string(), hex);
+    }
+
+    #[test]
+    fn parses_networks() {
+        let range = PeerRule::parse("192.168.1.0/24");
+        assert!(matches!(range, Some(PeerRule::Network(n)) if n.prefix() == 24));
+        let mapped = PeerRule::parse("::ffff:10.0.0.1");
+        assert!(matches!(mapped, Some(PeerRule::Network(n)) if n.is_ipv4()));
+        assert_eq!(PeerRule::parse("not a rule"), None);
+    }
+}
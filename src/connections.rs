@@ -1,27 +1,103 @@
 use crate::app::AppEvent::*;
-use crate::app::{random_color, AppEvent, Download, INFO};
+use crate::app::{random_color, AppEvent, Download, TransferSpeed, TransferState, INFO};
+use crate::config::Config;
 use crate::encryption::*;
+use crate::identity::{fingerprint, short_auth_string, verify_handshake, Identity};
+use crate::ratelimit::RateLimits;
+use crate::scheduler::Scheduler;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use crc_fast::checksum_file;
-use crc_fast::CrcAlgorithm::Crc32IsoHdlc;
+use image::RgbImage;
 use pnet::datalink;
-use pnet::ipnetwork::IpNetwork;
 use ratatui::prelude::{Line, Span, Style};
+use serde::{Deserialize, Serialize};
 use size::Size;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
 use std::fs;
-use std::io::Read;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, Instant};
-
-pub(crate) const CONNECTION_RETRIES: u16 = 10;
-const DOWNLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(10);
-///Piece size in bytes for sending files (64MiB)
-const PIECE_SIZE: u64 = ((2 ^ 10) ^ 2) * 64;
+use std::collections::HashMap;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+///Attempts made by `/connect` before giving up
+pub(crate) const CONNECTION_RETRIES: u32 = 10;
+///Wait after the first failed attempt to connect, doubled after each one after it
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+///Longest wait between attempts to connect
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+///Time allowed for a single attempt to connect
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+///Version of the wire protocol, bumped whenever the frames change,
+///even if older clients can still talk to us through [`CAPABILITIES`]
+pub(crate) const PROTOCOL_VERSION: u16 = 12;
+///Oldest protocol version we can still talk to
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 9;
+///Bit flags for optional features this client supports,
+///only features both peers support are used on a connection
+pub(crate) const CAPABILITIES: u64 = CAPABILITY_ZSTD | CAPABILITY_FILE_HASH | CAPABILITY_PING;
+///Capability to receive file pieces compressed with zstd, see [`MessageType::CompressedPiece`]
+const CAPABILITY_ZSTD: u64 = 1;
+///Capability to check whole transfers against a [`MessageType::FileHash`] before keeping them
+const CAPABILITY_FILE_HASH: u64 = 2;
+///Capability to answer a [`MessageType::Ping`], peers without it aren't pinged
+pub(crate) const CAPABILITY_PING: u64 = 4;
+///Time allowed for a new connection to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+///How often idle connections check if their key is due to be replaced
+const REKEY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+///How often transfers report their progress to the app
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(10);
+///How often each connection sends a [`MessageType::Ping`] to check the peer is still there
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+///Time after which a connection is dropped if a ping wasn't answered and nothing else arrived,
+///also the longest a write to the peer may block
+const PING_TIMEOUT: Duration = Duration::from_secs(45);
+///Time allowed for a [`MessageType::Goodbye`] to be sent before the connection is closed anyway
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+///Most answers to pings waiting to be sent, pings after that go unanswered
+///since the peer is sending them faster than it's supposed to
+const MAX_QUEUED_PONGS: usize = 4;
+///How often a download is synced to disk and its [`PartManifest`] updated
+const PART_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+///How often a paused upload checks if it was resumed
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+///Size in bytes of the first piece of a file, pieces then grow or shrink
+///so each takes about [`PIECE_TARGET_TIME`] to send
+const MIN_PIECE_SIZE: u64 = 64 * 1024;
+///Largest piece in bytes a peer may send, bounds the memory used to check and decompress them
+const MAX_PIECE_SIZE: u64 = 16 * 1024 * 1024;
+///Time a piece should take to send, long enough to keep the overhead per piece low
+///and short enough that pausing, cancelling or resending doesn't wait long
+const PIECE_TARGET_TIME: Duration = Duration::from_millis(250);
+///Bytes before the data in a [`MessageType::Piece`],
+///the transfer id, the offset of the data, and its BLAKE3 hash
+const PIECE_HEADER_SIZE: u64 = 48;
+///Largest part of a piece sent in one [`MessageType::Chunk`], small enough that
+///a message sent during a transfer doesn't wait long for the chunk ahead of it
+const CHUNK_SIZE: u64 = 16 * 1024;
+///Most pieces sent or put back together from their chunks at once on a connection,
+///bounds the memory a peer can make us use for them
+const MAX_PIECES_IN_PROGRESS: usize = 8;
+///Largest encoded [`Offer`] in bytes, apart from `max_message_size`
+///since a directory with many files needs a big one
+const MAX_OFFER_SIZE: u64 = 16 * 1024 * 1024;
+///Bytes before the data in a [`MessageType::Chunk`],
+///the transfer id, the message type of the piece, and 1 if it's the last chunk or 0 if not
+const CHUNK_HEADER_SIZE: u64 = 10;
+///Largest size of the thumbnail in an [`ImagePreview`] in pixels,
+///shown as half blocks so it takes half as many lines as it's tall
+pub(crate) const THUMBNAIL_SIZE: (u32, u32) = (48, 32);
+///Times in a row a piece can fail its hash check before the download is stopped
+const MAX_PIECE_RETRIES: u8 = 3;
+///Source of ids for uploads and downloads, starts at 1 to keep them short to type
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
 
 ///Struct to store the state of a connection
 #[derive(Debug)]
@@ -29,11 +105,38 @@ pub(crate) struct Connection {
     //pub(crate) local_addr: String,
     pub(crate) peer_addr: String,
     pub(crate) peer_nick: RwLock<Option<String>>,
-    ///Shared secret between two peers used for encryption,
-    ///generated by [`establish_shared_secret`]
-    secret: [u8; 32],
-    ///Lock to prevent sending multiple messages to the same peer at once
-    send_lock: Mutex<()>,
+    ///Client version the peer reported in its [`Hello`]
+    pub(crate) peer_version: String,
+    ///Addresses the peer reported it's listening on
+    pub(crate) peer_listen_addrs: Vec<String>,
+    ///Whether we connected to the peer, rather than it to us
+    pub(crate) outgoing: bool,
+    ///Fingerprint of the peer's identity key, see [`fingerprint`]
+    pub(crate) fingerprint: String,
+    ///Emoji to compare with the peer to verify the connection, see [`short_auth_string`]
+    pub(crate) sas: Vec<(&'static str, &'static str)>,
+    ///Whether the peer's fingerprint was marked as verified with `/trust`
+    pub(crate) verified: AtomicBool,
+    ///Optional features both peers support, see [`CAPABILITIES`]
+    pub(crate) capabilities: u64,
+    ///Files offered by the peer, keyed by the peer's transfer id
+    pub(crate) offers: Mutex<HashMap<u64, ReceivedOffer>>,
+    ///Limits for file transfers with this peer, see [`Config::peer_rate_limits`]
+    pub(crate) rate_limits: RateLimits,
+    ///Limits for file transfers with all peers together, shared with the app
+    global_rate_limits: Arc<RateLimits>,
+    ///Largest piece files are sent to the peer in, see [`Config::max_piece_size`]
+    max_piece_size: u64,
+    ///Key for messages sent to the peer, generated by [`derive_keys`],
+    ///also used as a lock to prevent sending multiple messages to the same peer at once
+    send_cipher: Mutex<CipherState>,
+    ///Decides which message is sent next when several are waiting
+    scheduler: Scheduler,
+    ///Pings waiting for an answer and the latency they measured, see [`keepalive`]
+    pub(crate) pings: Mutex<PingState>,
+    ///Reason the peer gave for disconnecting, set when it sends a [`MessageType::Goodbye`],
+    ///empty if it didn't give one
+    pub(crate) goodbye: Mutex<Option<String>>,
     pub(crate) stream: TcpStream,
     pub(crate) style: Style
 }
@@ -41,7 +144,7 @@ pub(crate) struct Connection {
 impl Connection {
     pub(crate) fn display_peer(&self, show_address: bool) -> Line<'static> {
         let style = self.style;
-        Line::<'static>::from(
+        let mut line = Line::<'static>::from(
             if let Some(n) = self.peer_nick.read().unwrap().clone() {
                 if show_address { vec![
                     Span::raw("<"),
@@ -59,7 +162,414 @@ impl Connection {
                     Span::styled(self.peer_addr.clone(), style),
                     Span::raw(">"),
             ] }
-        )
+        );
+        if show_address && self.verified.load(Ordering::Relaxed) {
+            line.push_span(Span::styled(" ✓", Style::new().green()));
+        }
+        if show_address && let Some(rtt) = self.pings.lock().unwrap().rtt {
+            line.push_span(Span::styled(format!(" {}ms", rtt.as_millis()), INFO));
+        }
+
+        line
+    }
+
+    ///Returns the name the peer is recorded under in the known peers file,
+    ///the address we connected to if we connected to it, otherwise just its IP
+    ///
+    ///Never the addresses the peer reports it listens on,
+    ///or an impersonator could pick one nobody is recorded under
+    pub(crate) fn known_host(&self) -> String {
+        match self.peer_ip() {
+            Some(ip) if !self.outgoing => ip.to_string(),
+            _ => self.peer_addr.clone()
+        }
+    }
+
+    ///Returns the address to reconnect to the peer at, the one it listens on for this IP
+    ///if it reported one, otherwise the first, with unspecified IPs replaced by the peer's
+    pub(crate) fn reconnect_addr(&self) -> Option<String> {
+        let ip = self.peer_addr.parse::<SocketAddr>().ok()?.ip();
+        let addrs: Vec<SocketAddr> = self.peer_listen_addrs.iter()
+            .filter_map(|a| a.parse::<SocketAddr>().ok())
+            .map(|a| if a.ip().is_unspecified() { SocketAddr::new(ip, a.port()) } else { a })
+            .collect();
+        addrs.iter().find(|a| a.ip() == ip).or(addrs.first()).map(|a| a.to_string())
+    }
+
+    ///Sends a [`MessageType::Ping`], `requested` is whether it was sent with `/ping`
+    ///and the app should be told when it's answered
+    pub(crate) fn ping(&self, requested: bool) -> Result<()> {
+        let id = {
+            let mut pings = self.pings.lock().unwrap();
+            pings.next_id += 1;
+            let id = pings.next_id;
+            pings.sent.insert(id, (Instant::now(), requested));
+            id
+        };
+        send_frame(self, &id.to_be_bytes(), &MessageType::Ping)
+    }
+
+    ///Returns the IP of the peer, without the scope id of a link-local IPv6 address
+    pub(crate) fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr.parse::<SocketAddr>().ok().map(|a| a.ip())
+    }
+
+    ///Returns whether both peers support the optional feature `capability`
+    pub(crate) fn supports(&self, capability: u64) -> bool {
+        self.capabilities & capability != 0
+    }
+
+    ///Returns whether `peer` is this peer's nick, address, or IP address
+    pub(crate) fn matches(&self, peer: &str) -> bool {
+        self.peer_nick.read().unwrap().as_deref() == Some(peer)
+            || self.peer_addr == peer
+            || self.peer_ip().is_some_and(|ip| {
+                peer.trim_start_matches('[').trim_end_matches(']').parse() == Ok(ip)
+            })
+    }
+}
+
+///A file or directory offered for transfer, sent before any of its contents
+#[derive(Debug, Clone)]
+pub(crate) struct Offer {
+    ///Id the sender uses for the transfer
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    ///Total size of all the files
+    pub(crate) size: u64,
+    ///What's being sent, see [`read_tree`]
+    pub(crate) entries: Vec<TreeEntry>
+}
+
+impl Offer {
+    ///Creates the offer for the files in `entries` under `path` with the given transfer `id`,
+    ///returns an error if `path` has no file name or there are too many files for the peer
+    pub(crate) fn new(id: u64, path: &Path, entries: Vec<TreeEntry>) -> Result<Self> {
+        let name = path.file_name().ok_or_else(|| eyre!("It has no name"))?;
+        let offer = Self {
+            id,
+            name: name.to_string_lossy().to_string(),
+            size: entries.iter().map(|e| e.size).sum(),
+            entries
+        };
+        if offer.to_bytes().len() as u64 > MAX_OFFER_SIZE {
+            return Err(eyre!("Too many files, the list of them is over {}",
+                Size::from_bytes(MAX_OFFER_SIZE)));
+        }
+
+        Ok(offer)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(22 + self.name.len());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        put_str(&mut bytes, &self.name);
+        bytes.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            put_str(&mut bytes, &entry.path);
+            bytes.extend_from_slice(&entry.size.to_be_bytes());
+            bytes.extend_from_slice(&entry.modified.to_be_bytes());
+            bytes.push(entry.dir as u8);
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = FieldReader(bytes);
+        let id = reader.u64()?;
+        let size = reader.u64()?;
+        let name = reader.str()?;
+        let mut entries = vec![];
+        for _ in 0..reader.u32()? {
+            entries.push(TreeEntry {
+                path: reader.str()?,
+                size: reader.u64()?,
+                modified: reader.u64()?,
+                dir: reader.array::<1>()?[0] == 1
+            });
+        }
+        Ok(Self { id, name, size, entries })
+    }
+
+    ///Returns whether a directory is offered rather than a single file
+    pub(crate) fn is_dir(&self) -> bool {
+        self.entries.first().is_some_and(|e| e.dir)
+    }
+
+    ///Returns the offered name, ending in `/` for a directory
+    pub(crate) fn display_name(&self) -> String {
+        if self.is_dir() { format!("{}/", self.name) } else { self.name.clone() }
+    }
+
+    ///Returns whether the entries form a tree that can be written under the download directory,
+    ///the first one being the offered file or directory itself
+    fn is_valid(&self) -> bool {
+        let Some((root, rest)) = self.entries.split_first() else {
+            return false;
+        };
+        root.path.is_empty()
+            && (root.dir || rest.is_empty())
+            && rest.iter().all(|e| !e.path.is_empty() && entry_path(Path::new(""), e).is_some())
+            && self.entries.iter().all(|e| !e.dir || e.size == 0)
+            && self.entries.iter().try_fold(0u64, |total, e| total.checked_add(e.size))
+                == Some(self.size)
+    }
+}
+
+///A file or directory in an [`Offer`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TreeEntry {
+    ///Path relative to the offered directory with `/` between components,
+    ///empty for the offered file or directory itself
+    pub(crate) path: String,
+    ///Size of a file, 0 for a directory
+    pub(crate) size: u64,
+    ///Modification time in seconds since the Unix epoch
+    pub(crate) modified: u64,
+    pub(crate) dir: bool
+}
+
+///An [`Offer`] received from a peer along with its local state
+#[derive(Debug)]
+pub(crate) struct ReceivedOffer {
+    ///Id of the matching [`Download`]
+    pub(crate) download_id: u64,
+    pub(crate) offer: Offer,
+    pub(crate) accepted: bool,
+    ///Earlier download of the same file from the same peer that was interrupted,
+    ///or the file being written once the peer starts sending
+    pub(crate) partial: Option<PartialDownload>,
+    ///When the app was last sent the download's progress
+    last_progress: Instant,
+    ///Times in a row a piece failed its hash check, see [`MAX_PIECE_RETRIES`]
+    retries: u8
+}
+
+impl ReceivedOffer {
+    ///Returns the offset to ask the peer to start sending from
+    pub(crate) fn offset(&self) -> u64 {
+        self.partial.as_ref().map_or(0, |p| p.manifest.received)
+    }
+
+    ///Returns whether the peer has started sending the file
+    fn started(&self) -> bool {
+        self.partial.as_ref().is_some_and(|p| p.started())
+    }
+
+    ///Stops the download, removing the partial file if `discard` is set
+    ///or keeping it to resume from otherwise
+    pub(crate) fn cancel(self, discard: bool) -> Result<()> {
+        match self.partial {
+            Some(p) if discard => p.discard(),
+            Some(p) => p.close(),
+            None => Ok(())
+        }
+    }
+}
+
+///Change to the state of a transfer, sent in a [`MessageType::Control`] message
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransferAction {
+    Pause = 0u8,
+    Resume = 1u8,
+    ///Stops the transfer, keeping the partial file so it can be resumed later
+    Cancel = 2u8,
+    ///Stops the transfer and removes the partial file
+    Discard = 3u8
+}
+
+impl TryFrom<u8> for TransferAction {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Pause),
+            1 => Ok(Self::Resume),
+            2 => Ok(Self::Cancel),
+            3 => Ok(Self::Discard),
+            _ => Err(())
+        }
+    }
+}
+
+///Flags for pausing or cancelling an upload from outside the thread sending it
+#[derive(Debug, Default)]
+pub(crate) struct UploadControl {
+    pub(crate) paused: AtomicBool,
+    pub(crate) cancelled: AtomicBool,
+    ///Offset the peer asked to have the file sent again from after a piece was corrupted
+    pub(crate) resend_from: Mutex<Option<u64>>
+}
+
+///First message sent over a new connection by both peers,
+///used to refuse incompatible clients and negotiate optional features
+#[derive(Debug, Clone)]
+pub(crate) struct Hello {
+    pub(crate) protocol_version: u16,
+    pub(crate) capabilities: u64,
+    ///Version of the client, from `Cargo.toml`
+    pub(crate) client_version: String,
+    pub(crate) listen_addrs: Vec<String>,
+    ///Public half of the sender's long-term [`Identity`]
+    pub(crate) identity_key: [u8; 32],
+    ///Signature of the handshake's ephemeral keys made with the identity key
+    pub(crate) signature: [u8; 64]
+}
+
+impl Hello {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.protocol_version.to_be_bytes());
+        bytes.extend_from_slice(&self.capabilities.to_be_bytes());
+        put_str(&mut bytes, &self.client_version);
+        bytes.extend_from_slice(&(self.listen_addrs.len() as u16).to_be_bytes());
+        for addr in &self.listen_addrs {
+            put_str(&mut bytes, addr);
+        }
+        bytes.extend_from_slice(&self.identity_key);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = FieldReader(bytes);
+        let protocol_version = reader.u16()?;
+        let capabilities = reader.u64()?;
+        let client_version = reader.str()?;
+        let mut listen_addrs = vec![];
+        for _ in 0..reader.u16()? {
+            listen_addrs.push(reader.str()?);
+        }
+        Ok(Self {
+            protocol_version,
+            capabilities,
+            client_version,
+            listen_addrs,
+            identity_key: reader.array()?,
+            signature: reader.array()?
+        })
+    }
+}
+
+///Preview of an image sent with `/img`, sent ahead of the [`Offer`] for the image itself
+#[derive(Debug, Clone)]
+pub(crate) struct ImagePreview {
+    pub(crate) name: String,
+    ///Size of the full image in pixels
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    ///The image shrunk to fit within [`THUMBNAIL_SIZE`]
+    pub(crate) thumbnail: RgbImage
+}
+
+impl ImagePreview {
+    ///Decodes the image at `path` and makes a thumbnail of it
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let image = image::open(path)?;
+        Ok(Self {
+            name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            width: image.width(),
+            height: image.height(),
+            thumbnail: image.thumbnail(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1).to_rgb8()
+        })
+    }
+
+    ///Encodes the preview for the image offered with the transfer id `id`
+    fn to_bytes(&self, id: u64) -> Vec<u8> {
+        let mut bytes = id.to_be_bytes().to_vec();
+        put_str(&mut bytes, &self.name);
+        bytes.extend_from_slice(&self.width.to_be_bytes());
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&(self.thumbnail.width() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.thumbnail.height() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.thumbnail.as_raw());
+        bytes
+    }
+
+    ///Decodes a preview, returns it with the transfer id of the image
+    fn from_bytes(bytes: &[u8]) -> Result<(u64, Self)> {
+        let mut reader = FieldReader(bytes);
+        let id = reader.u64()?;
+        let name = reader.str()?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let thumbnail_width = reader.u16()? as u32;
+        let thumbnail_height = reader.u16()? as u32;
+        if thumbnail_width > THUMBNAIL_SIZE.0 || thumbnail_height > THUMBNAIL_SIZE.1 {
+            return Err(eyre!("sent an invalid image preview"));
+        }
+        let pixels = reader.bytes((thumbnail_width * thumbnail_height * 3) as usize)?;
+        let thumbnail = RgbImage::from_raw(thumbnail_width, thumbnail_height, pixels.to_vec())
+            .ok_or_else(|| eyre!("sent an invalid image preview"))?;
+
+        Ok((id, Self { name, width, height, thumbnail }))
+    }
+}
+
+///Reads fields from the front of a decrypted message
+pub(crate) struct FieldReader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> FieldReader<'a> {
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(eyre!("sent a truncated message"));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into()?)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    ///Reads a string prefixed with its length as a [`u16`]
+    pub(crate) fn str(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+}
+
+///Appends `s` to `bytes` prefixed with its length as a [`u16`], truncating it if needed
+pub(crate) fn put_str(bytes: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    bytes.extend_from_slice(&(len as u16).to_be_bytes());
+    bytes.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+///Pings sent to a peer and what they measured
+#[derive(Debug)]
+pub(crate) struct PingState {
+    ///Ids of the pings that weren't answered yet,
+    ///when they were sent and whether they were sent with `/ping`
+    sent: HashMap<u64, (Instant, bool)>,
+    next_id: u64,
+    ///When the last message from the peer arrived
+    last_received: Instant,
+    ///Round-trip time of the last ping that was answered
+    pub(crate) rtt: Option<Duration>
+}
+
+impl Default for PingState {
+    fn default() -> Self {
+        Self { sent: HashMap::new(), next_id: 0, last_received: Instant::now(), rtt: None }
     }
 }
 
@@ -69,8 +579,47 @@ impl Connection {
 pub(crate) enum MessageType {
     Text = 255u8,
     File = 254u8,
+    ///See [`ImagePreview`]
     Image = 253u8,
-    Command = 252u8
+    Command = 252u8,
+    ///File offer, see [`Offer`]
+    Offer = 251u8,
+    ///Answer to an [`Offer`], the offer id followed by 1 if it was accepted or 0 if not,
+    ///then the offset to start sending from
+    Answer = 250u8,
+    ///See [`Hello`]
+    Hello = 249u8,
+    ///Empty message telling the peer that the sender's key is replaced after it,
+    ///see [`CipherState::ratchet`]
+    Rekey = 248u8,
+    ///Part of a file, starting with a header of [`PIECE_HEADER_SIZE`] followed by the data,
+    ///only sent split into [`MessageType::Chunk`]s
+    Piece = 247u8,
+    ///Changes the state of a transfer, the transfer id, 1 if the sender of the message
+    ///is the one uploading or 0 if not, then a [`TransferAction`]
+    Control = 246u8,
+    ///[`MessageType::Piece`] with the data compressed with zstd,
+    ///only sent if both peers have [`CAPABILITY_ZSTD`],
+    ///the hash in the header is of the data before compression
+    CompressedPiece = 245u8,
+    ///Asks the sender of a file to send it again from an offset after a piece failed
+    ///its hash check, or from the start if the part file to resume couldn't be opened,
+    ///the transfer id followed by the offset
+    Resend = 244u8,
+    ///Part of a [`MessageType::Piece`] or [`MessageType::CompressedPiece`],
+    ///starting with a header of [`CHUNK_HEADER_SIZE`],
+    ///chunks of pieces of different transfers can be sent in between each other
+    Chunk = 243u8,
+    ///Asks the peer to answer with a [`MessageType::Pong`], the ping id
+    Ping = 242u8,
+    ///Answer to a [`MessageType::Ping`] with the same id
+    Pong = 241u8,
+    ///Tells the peer the sender is disconnecting on purpose, followed by the reason if any,
+    ///so it doesn't try to reconnect, older clients skip it like any message they don't know
+    Goodbye = 240u8,
+    ///BLAKE3 hash of all the data of a transfer, the transfer id followed by the hash,
+    ///sent after the last piece, only if both peers have [`CAPABILITY_FILE_HASH`]
+    FileHash = 239u8
 }
 
 impl TryFrom<u8> for MessageType {
@@ -78,28 +627,61 @@ impl TryFrom<u8> for MessageType {
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Ok(Self::Text),
+            255 => Ok(Self::Text),
             254 => Ok(Self::File),
             253 => Ok(Self::Image),
             252 => Ok(Self::Command),
-            _ => Err(Self::Error::default())
+            251 => Ok(Self::Offer),
+            250 => Ok(Self::Answer),
+            249 => Ok(Self::Hello),
+            248 => Ok(Self::Rekey),
+            247 => Ok(Self::Piece),
+            246 => Ok(Self::Control),
+            245 => Ok(Self::CompressedPiece),
+            244 => Ok(Self::Resend),
+            243 => Ok(Self::Chunk),
+            242 => Ok(Self::Ping),
+            241 => Ok(Self::Pong),
+            240 => Ok(Self::Goodbye),
+            239 => Ok(Self::FileHash),
+            _ => Err(())
         }
     }
 }
 
 ///Starts a [`TcpListener`] on `listen_addr`
 ///and sends each incoming [`TcpStream`] to the app as a [`NewStream`] event
-pub(crate) fn connection_listener(tx: Sender<AppEvent>, listen_addr: &str) -> Result<()> {
-    if let Ok(listener) = TcpListener::bind(listen_addr) {
+pub(crate) fn connection_listener(
+    tx: Sender<AppEvent>,
+    config: Arc<RwLock<Config>>,
+    listen_addr: &str
+) -> Result<()> {
+    let listener = resolve_addr(listen_addr)
+        .and_then(|a| a.first().copied().ok_or(io::ErrorKind::AddrNotAvailable.into()))
+        .and_then(bind_listener);
+    if let Ok(listener) = listener {
         let local_addr = listener.local_addr()?.to_string();
         tx.send(MessageEvent(Line::from(Span::styled(
             format!("Listening on {local_addr}..."), INFO
         ))))?;
         tx.send(ListenEvent(local_addr.clone()))?;
-        for stream in listener.incoming() {
-            if let Ok(s) = stream {
-                tx.send(NewStream(s))?;
+        for s in listener.incoming().flatten() {
+            let Ok(peer_addr) = s.peer_addr().map(canonical_addr) else {
+                continue;
+            };
+            //refuse blocked addresses before spending a handshake on them
+            let config = config.read().unwrap();
+            if !config.is_allowed(peer_addr.ip(), None) {
+                let _ = s.shutdown(Shutdown::Both);
+                if config.debug {
+                    tx.send(MessageEvent(Line::from(Span::styled(
+                        format!("Refused connection from {peer_addr}, blocked"), INFO
+                    ))))?;
+                }
+                continue;
             }
+            drop(config);
+            tx.send(NewStream(s, false))?;
         }
     } else {
         tx.send(ErrorEvent(format!(
@@ -110,59 +692,238 @@ pub(crate) fn connection_listener(tx: Sender<AppEvent>, listen_addr: &str) -> Re
     Ok(())
 }
 
+///Tries to connect to `addr` until it succeeds, `cancelled` is set,
+///or `max_attempts` attempts failed, waiting longer after each failure, see [`retry_delay`]
+///
+///Sends the stream to the app as a [`NewStream`] event, a [`RetryEvent`] every second
+///while waiting to try again, and a [`ConnectDoneEvent`] when it stops trying
+pub(crate) fn connect_with_retries(
+    tx: Sender<AppEvent>,
+    running: Arc<AtomicBool>,
+    addr: String,
+    cancelled: Arc<AtomicBool>,
+    max_attempts: Option<u32>
+) -> Result<()> {
+    let stopped = || !running.load(Ordering::Relaxed) || cancelled.load(Ordering::Relaxed);
+    let mut attempt = 0;
+    while !stopped() {
+        let stream = resolve_addr(&addr).and_then(|addrs| {
+            addrs.iter()
+                .map(|a| TcpStream::connect_timeout(a, CONNECT_TIMEOUT))
+                .find(Result::is_ok)
+                .unwrap_or_else(|| Err(io::ErrorKind::ConnectionRefused.into()))
+        });
+        let error = match stream {
+            Ok(s) => {
+                tx.send(NewStream(s, true))?;
+                break;
+            }
+            //trying again won't fix an address that isn't one
+            Err(e) if matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::NotFound) => {
+                tx.send(ErrorEvent(format!("Failed to connect to {addr}: {e}")))?;
+                break;
+            }
+            Err(e) => e
+        };
+        attempt += 1;
+        if max_attempts.is_some_and(|m| attempt >= m) {
+            tx.send(ErrorEvent(format!("Failed to connect to {addr}: {error}")))?;
+            break;
+        }
+        let next_attempt = Instant::now() + retry_delay(attempt);
+        while !stopped() && Instant::now() < next_attempt {
+            tx.send(RetryEvent(addr.clone(), attempt, next_attempt))?;
+            let left = next_attempt.saturating_duration_since(Instant::now());
+            sleep(left.min(Duration::from_secs(1)));
+        }
+    }
+    tx.send(ConnectDoneEvent(addr))?;
+
+    Ok(())
+}
+
+///Returns how long to wait after `attempt` failed attempts to connect,
+///doubling from [`RETRY_MIN_DELAY`] up to [`RETRY_MAX_DELAY`], then cut by up to half at random
+///so peers that lost each other at the same moment don't retry in lockstep
+fn retry_delay(attempt: u32) -> Duration {
+    let delay = RETRY_MIN_DELAY.saturating_mul(1 << (attempt - 1).min(16)).min(RETRY_MAX_DELAY);
+    delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+}
+
+///Establishes a [`Connection`] over `stream`, sends it to the app as a [`ConnectionEvent`],
+///then handles incoming messages until the peer disconnects
+///
+///`outgoing` is whether we connected to the peer
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn connection_handler(
     tx: Sender<AppEvent>,
     running: Arc<AtomicBool>,
-    mut stream: TcpStream
+    config: Arc<RwLock<Config>>,
+    listen_addrs: Arc<RwLock<Vec<String>>>,
+    identity: Arc<Identity>,
+    global_rate_limits: Arc<RateLimits>,
+    mut stream: TcpStream,
+    outgoing: bool
 ) -> Result<()> {
-    let mut header = [0u8; 8];
-    let mut buf: Vec<u8> = vec![];
     //let local_addr = stream.local_addr()?.to_string();
-    let peer_addr = stream.peer_addr()?.to_string();
-    let secret = if let Ok(s) = establish_shared_secret(&mut stream) {
-        s
+    let peer_addr = canonical_addr(stream.peer_addr()?).to_string();
+    //don't let a peer that never answers hold the handshake forever
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let (limits, psk) = {
+        let config = config.read().unwrap();
+        let limits = RekeyLimits {
+            messages: config.rekey_messages,
+            bytes: config.rekey_bytes,
+            interval: Duration::from_secs(config.rekey_minutes * 60)
+        };
+        (limits, config.psk.clone())
+    };
+    let keys = establish_shared_secret(&mut stream, psk.as_deref())
+        .and_then(|h| derive_keys(&h, limits).map(|(send, recv)| (h, send, recv)));
+    let (handshake, mut send_cipher, mut recv_cipher) = if let Ok(k) = keys {
+        k
     } else {
         tx.send(ErrorEvent(format!(
             "Failed to establish shared secret with {peer_addr}"
         )))?;
         return Ok(());
     };
+    let hello = Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        listen_addrs: listen_addrs.read().unwrap().clone(),
+        identity_key: identity.public_key(),
+        signature: identity.sign_handshake(&handshake)
+    };
+    let (max_message_size, max_piece_size) = {
+        let config = config.read().unwrap();
+        (config.max_message_size, config.max_piece_size.clamp(MIN_PIECE_SIZE, MAX_PIECE_SIZE))
+    };
+    let peer_hello = match exchange_hello(
+        &stream, &handshake, (&mut send_cipher, &mut recv_cipher), &hello, max_message_size
+    ) {
+        Ok(h) => h,
+        Err(e) => {
+            let _ = stream.shutdown(Shutdown::Both);
+            tx.send(ErrorEvent(format!("Refused connection from {peer_addr}: {e}")))?;
+            return Ok(());
+        }
+    };
+    let sas = short_auth_string(&handshake, &hello.identity_key, &peer_hello.identity_key);
+    //the root secret could be used to derive every key of the session, wipe it right away
+    drop(handshake);
+    let peer_fingerprint = fingerprint(&peer_hello.identity_key);
+    if !config.read().unwrap().is_allowed(stream.peer_addr()?.ip(), Some(&peer_fingerprint)) {
+        let _ = stream.shutdown(Shutdown::Both);
+        tx.send(ErrorEvent(format!("Refused connection from {peer_addr}, blocked")))?;
+        return Ok(());
+    }
+    stream.set_read_timeout(None)?;
+    //a peer that stopped reading fails writes instead of blocking them forever
+    stream.set_write_timeout(Some(PING_TIMEOUT))?;
+    let (max_upload_rate, max_download_rate) = config.read().unwrap()
+        .peer_rate_limit(stream.peer_addr()?.ip(), &peer_fingerprint);
     let connection: Arc<Connection> = Arc::new(Connection {
         //local_addr,
         peer_addr,
         peer_nick: RwLock::new(None),
-        secret,
-        send_lock: Mutex::new(()),
+        peer_version: peer_hello.client_version,
+        peer_listen_addrs: peer_hello.listen_addrs,
+        outgoing,
+        fingerprint: peer_fingerprint,
+        sas,
+        verified: AtomicBool::new(false),
+        capabilities: CAPABILITIES & peer_hello.capabilities,
+        offers: Mutex::new(HashMap::new()),
+        rate_limits: RateLimits::new(max_upload_rate, max_download_rate),
+        global_rate_limits,
+        max_piece_size,
+        send_cipher: Mutex::new(send_cipher),
+        scheduler: Scheduler::default(),
+        pings: Mutex::default(),
+        goodbye: Mutex::new(None),
         stream,
         style: Style::new().fg(random_color())
     });
-    let mut reader = BufReader::new(&connection.stream);
 
     tx.send(ConnectionEvent(connection.clone()))?;
+    if !limits.interval.is_zero() {
+        let c = Arc::downgrade(&connection);
+        let r = running.clone();
+        spawn(move || rekey_timer(c, r));
+    }
+    if connection.supports(CAPABILITY_PING) {
+        let (t, c, r) = (tx.clone(), Arc::downgrade(&connection), running.clone());
+        spawn(move || keepalive(t, c, r));
+    }
+
+    if let Err(e) = receive_messages(&tx, &running, &config, &connection, &mut recv_cipher) {
+        //the peer misbehaved or the connection broke mid-message, drop it
+        let _ = connection.stream.shutdown(Shutdown::Both);
+        tx.send(ErrorEvent(format!(
+            "Dropped connection to {}: {e}", connection.peer_addr
+        )))?;
+    }
+    tx.send(DisconnectionEvent(connection.peer_addr.clone()))?;
+
+    Ok(())
+}
+
+///Reads and handles messages from `connection` until the stream is closed,
+///returns an error if the peer sends something invalid
+fn receive_messages(
+    tx: &Sender<AppEvent>,
+    running: &AtomicBool,
+    config: &RwLock<Config>,
+    connection: &Arc<Connection>,
+    cipher: &mut CipherState
+) -> Result<()> {
+    let mut header = [0u8; 8];
+    let mut buf: Vec<u8> = vec![];
+    let mut reader = BufReader::new(&connection.stream);
+    let (max_message_size, max_file_size, download_dir) = {
+        let config = config.read().unwrap();
+        (config.max_message_size, config.max_file_size, config.download_dir.clone())
+    };
+    //pieces being put back together from their chunks, keyed by transfer id,
+    //and buffers of finished ones to reuse
+    let mut pieces: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut spare_pieces: Vec<Vec<u8>> = vec![];
+    let mut decompressor = PieceDecompressor::new()?;
+    //answering pings here could block reading while the peer is blocked sending to us,
+    //so one thread sends the answers until this returns
+    let (pong_tx, pong_rx) = sync_channel::<Vec<u8>>(MAX_QUEUED_PONGS);
+    let c = connection.clone();
+    spawn(move || {
+        for pong in pong_rx {
+            if send_frame(&c, &pong, &MessageType::Pong).is_err() {
+                break;
+            }
+        }
+    });
 
     while running.load(Ordering::Relaxed) {
-        if let Err(_) = reader.read_exact(&mut header) {
-            tx.send(DisconnectionEvent(connection.peer_addr.clone()))?;
+        if reader.read_exact(&mut header).is_err() {
             return Ok(());
         } else {
-            let msg_type = match MessageType::try_from(header[0]) {
-                Ok(msg_type) => msg_type,
-                _ => MessageType::Text
+            connection.pings.lock().unwrap().last_received = Instant::now();
+            let Ok(msg_type) = MessageType::try_from(header[0]) else {
+                skip_frame(&mut reader, &mut buf, &header, cipher, max_message_size)?;
+                continue;
             };
             header[0] = 0;
             match msg_type {
                 MessageType::Text => {
-                    buf.resize(usize::from_be_bytes(header), 0);
-                    reader.read_exact(&mut buf)?;
-                    let line = String::from_utf8(decrypt(&buf, &connection.secret)?)?;
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let line = String::from_utf8(cipher.decrypt(&buf, msg_type as u8)?)?;
                     let mut message = connection.display_peer(false);
                     message.push_span(format!(" {line}"));
                     tx.send(MessageEvent(message))?;
                 }
                 MessageType::Command => {
-                    buf.resize(usize::from_be_bytes(header), 0);
-                    reader.read_exact(&mut buf)?;
-                    let line = String::from_utf8(decrypt(&buf, &connection.secret)?)?;
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let line = String::from_utf8(cipher.decrypt(&buf, msg_type as u8)?)?;
                     let mut parts = line.splitn(2, ' ');
                     if let Some(cmd) = parts.next()
                         && let Some(arg) = parts.next() {
@@ -183,62 +944,305 @@ pub(crate) fn connection_handler(
                         }
                     }
                 }
+                MessageType::Image => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let image = cipher.decrypt(&buf, msg_type as u8)?;
+                    let (id, preview) = ImagePreview::from_bytes(&image)?;
+                    tx.send(ImageEvent(connection.clone(), id, preview))?;
+                }
+                MessageType::Goodbye => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let reason = String::from_utf8(cipher.decrypt(&buf, msg_type as u8)?)?;
+                    connection.goodbye.lock().unwrap().replace(reason);
+                    return Ok(());
+                }
+                MessageType::Rekey => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    cipher.decrypt(&buf, msg_type as u8)?;
+                    cipher.ratchet()?;
+                }
+                MessageType::Offer => {
+                    let max_offer_size = MAX_OFFER_SIZE + ENCRYPTION_OVERHEAD;
+                    read_frame(&mut reader, &mut buf, &header, max_offer_size)?;
+                    let offer = Offer::from_bytes(&cipher.decrypt(&buf, msg_type as u8)?)?;
+                    if !offer.is_valid() {
+                        tx.send(ErrorEvent(format!(
+                            "Rejected \"{}\" from {}, invalid list of files",
+                            offer.name, connection.peer_addr
+                        )))?;
+                        send_answer(connection.clone(), offer.id, None)?;
+                        continue;
+                    }
+                    if offer.size > max_file_size {
+                        tx.send(ErrorEvent(format!(
+                            "Rejected file \"{}\" from {}, {} is over the {} limit",
+                            offer.name, connection.peer_addr,
+                            Size::from_bytes(offer.size), Size::from_bytes(max_file_size)
+                        )))?;
+                        send_answer(connection.clone(), offer.id, None)?;
+                        continue;
+                    }
+                    let partial = PartialDownload::find(&download_dir, &connection.fingerprint, &offer);
+                    let received = ReceivedOffer {
+                        download_id: next_transfer_id(),
+                        offer,
+                        accepted: false,
+                        partial,
+                        last_progress: Instant::now(),
+                        retries: 0
+                    };
+                    let download = Download {
+                        connection: connection.clone(),
+                        id: received.download_id,
+                        transfer_id: received.offer.id,
+                        path: received.offer.display_name(),
+                        progress: Size::from_bytes(received.offset()),
+                        size: Size::from_bytes(received.offer.size),
+                        speed: TransferSpeed::new(received.offset()),
+                        state: TransferState::Pending
+                    };
+                    connection.offers.lock().unwrap().insert(received.offer.id, received);
+                    tx.send(OfferEvent(download))?;
+                }
+                MessageType::Answer => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let answer = cipher.decrypt(&buf, msg_type as u8)?;
+                    if answer.len() != 17 {
+                        return Err(eyre!("sent an invalid answer to a file offer"));
+                    }
+                    let id = u64::from_be_bytes(answer[..8].try_into()?);
+                    let offset = u64::from_be_bytes(answer[9..].try_into()?);
+                    tx.send(OfferAnswerEvent(
+                        connection.peer_addr.clone(), id, (answer[8] == 1).then_some(offset)
+                    ))?;
+                }
                 MessageType::File => {
-                    let file_size = u64::from_be_bytes(header);
-                    reader.read_exact(&mut header)?;
-                    header[0] = 0;
-                    buf.resize(u64::from_be_bytes(header) as usize, 0);
-                    reader.read_exact(&mut buf)?;
-                    let crc = u64::from_be_bytes(decrypt(
-                        &buf, &connection.secret
-                    )?.try_into().unwrap());
-                    reader.read_exact(&mut header)?;
-                    buf.resize(u64::from_be_bytes(header) as usize, 0);
-                    reader.read_exact(&mut buf)?;
-                    let file_name = String::from_utf8(decrypt(&buf, &connection.secret)?)?;
-
-                    if let Some((mut file, new_path)) = try_create_file(&file_name) {
-                        let mut buf_writer = BufWriter::new(&mut file);
-                        let pieces = (file_size + PIECE_SIZE - 1) / PIECE_SIZE;
-                        let mut progress = 0;
-                        let download_id = fastrand::u64(..);
-                        tx.send(DownloadEvent(Download {
-                            id: download_id,
-                            connection: connection.clone(),
-                            path: new_path.clone(),
-                            progress: Size::from_bytes(progress),
-                            size: Size::from_bytes(file_size)
-                        }))?;
-                        let mut last = Instant::now();
-                        for _piece in 0..pieces {
-                            reader.read_exact(&mut header)?;
-                            buf.resize(u64::from_be_bytes(header) as usize, 0);
-                            reader.read_exact(&mut buf)?;
-                            let bytes = decrypt(&buf, &connection.secret)?;
-                            buf_writer.write_all(&bytes)?;
-                            progress += bytes.len() as u64;
-                            if last.elapsed() >= DOWNLOAD_PROGRESS_INTERVAL {
-                                tx.send(DownloadProgressEvent(download_id, progress))?;
-                                last = Instant::now();
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let file_header = cipher.decrypt(&buf, msg_type as u8)?;
+                    if file_header.len() != 16 {
+                        return Err(eyre!("sent an invalid file header"));
+                    }
+                    let id = u64::from_be_bytes(file_header[..8].try_into()?);
+                    let offset = u64::from_be_bytes(file_header[8..].try_into()?);
+                    let mut offers = connection.offers.lock().unwrap();
+                    let Some(received) = offers.get_mut(&id).filter(|r| r.accepted && !r.started())
+                    else {
+                        return Err(eyre!("sent a file that wasn't accepted"));
+                    };
+                    if offset != received.offset() {
+                        return Err(eyre!("sent a file from the wrong offset"));
+                    }
+                    let create = || PartialDownload::create(
+                        &download_dir, &connection.fingerprint, received.offer.clone()
+                    );
+                    let mut restarted = false;
+                    let opened = match received.partial.take() {
+                        Some(mut p) => match p.open() {
+                            Ok(()) => Some(p),
+                            Err(e) => {
+                                tx.send(ErrorEvent(format!(
+                                    "Failed to resume \"{}\" from {}, starting over: {e}",
+                                    received.offer.name, connection.peer_addr
+                                )))?;
+                                let _ = p.discard();
+                                restarted = true;
+                                create()
                             }
+                        },
+                        None => create()
+                    };
+                    let Some(part) = opened else {
+                        let received = offers.remove(&id).unwrap();
+                        drop(offers);
+                        tx.send(DownloadCompleteEvent(received.download_id))?;
+                        tx.send(ErrorEvent(format!(
+                            "Refused file \"{}\" from {}, invalid file name",
+                            received.offer.name, connection.peer_addr
+                        )))?;
+                        send_control(connection, id, false, TransferAction::Cancel)?;
+                        continue;
+                    };
+                    tx.send(DownloadEvent(Download {
+                        id: received.download_id,
+                        connection: connection.clone(),
+                        transfer_id: id,
+                        path: part.path.to_string_lossy().to_string(),
+                        progress: Size::from_bytes(part.written),
+                        size: Size::from_bytes(received.offer.size),
+                        speed: TransferSpeed::new(part.written),
+                        state: TransferState::Active
+                    }))?;
+                    //with a hash to check, the download only ends once it arrives
+                    let done = part.written == received.offer.size
+                        && !connection.supports(CAPABILITY_FILE_HASH);
+                    received.partial = Some(part);
+                    if done {
+                        let received = offers.remove(&id).unwrap();
+                        drop(offers);
+                        finish_download(tx, connection, &download_dir, received)?;
+                    } else if restarted {
+                        drop(offers);
+                        //pieces from the old offset are dropped until the peer goes back
+                        send_resend(connection, id, 0)?;
+                    }
+                }
+                MessageType::Chunk => {
+                    let max_chunk_size = CHUNK_SIZE + CHUNK_HEADER_SIZE + ENCRYPTION_OVERHEAD;
+                    read_frame(&mut reader, &mut buf, &header, max_chunk_size)?;
+                    //not reading from the stream slows the peer down once its buffers fill up
+                    connection.global_rate_limits.download.throttle(buf.len() as u64);
+                    connection.rate_limits.download.throttle(buf.len() as u64);
+                    let chunk = cipher.decrypt(&buf, msg_type as u8)?;
+                    if chunk.len() < CHUNK_HEADER_SIZE as usize {
+                        return Err(eyre!("sent an invalid chunk of a file"));
+                    }
+                    let (chunk_header, data) = chunk.split_at(CHUNK_HEADER_SIZE as usize);
+                    let id = u64::from_be_bytes(chunk_header[..8].try_into()?);
+                    let compressed = match MessageType::try_from(chunk_header[8]) {
+                        Ok(MessageType::Piece) => false,
+                        Ok(MessageType::CompressedPiece) => true,
+                        _ => return Err(eyre!("sent a chunk of something other than a file"))
+                    };
+                    let started = match connection.offers.lock().unwrap().get(&id) {
+                        Some(r) => r.accepted && r.started(),
+                        //chunks of cancelled downloads are dropped like their pieces
+                        None => {
+                            pieces.remove(&id);
+                            continue;
+                        }
+                    };
+                    if !started {
+                        return Err(eyre!("sent part of a file before starting it"));
+                    }
+                    if !pieces.contains_key(&id) {
+                        //downloads cancelled halfway through a piece don't count
+                        if pieces.len() >= MAX_PIECES_IN_PROGRESS {
+                            let offers = connection.offers.lock().unwrap();
+                            pieces.retain(|id, _| offers.contains_key(id));
+                        }
+                        if pieces.len() >= MAX_PIECES_IN_PROGRESS {
+                            return Err(eyre!("sent too many pieces of files at once"));
                         }
-                        buf_writer.flush()?;
-                        tx.send(DownloadCompleteEvent(download_id))?;
-                        let mut message = Line::from(Span::styled(
-                            format!("Received file \"{file_name}\" from "), INFO
-                        ));
-                        let peer = connection.display_peer(false).spans;
-                        message.spans.extend(peer);
-                        tx.send(MessageEvent(message))?;
-                        let file_crc = checksum_file(Crc32IsoHdlc, &new_path, None)?;
-                        if file_crc != crc {
-                            tx.send(ErrorEvent(
-                                format!("Error: Received file \"{file_name}\" failed checksum, possibly corrupted")
-                            ))?;
+                        pieces.insert(id, spare_pieces.pop().unwrap_or_default());
+                    }
+                    let piece = pieces.get_mut(&id).unwrap();
+                    if piece.len() + data.len() > (MAX_PIECE_SIZE + PIECE_HEADER_SIZE) as usize {
+                        return Err(eyre!("sent a piece of a file that's too big"));
+                    }
+                    piece.extend_from_slice(data);
+                    if chunk_header[9] == 1 {
+                        let mut piece = pieces.remove(&id).unwrap();
+                        let d = compressed.then_some(&mut decompressor);
+                        receive_piece(tx, connection, &download_dir, &piece, d)?;
+                        piece.clear();
+                        spare_pieces.push(piece);
+                    }
+                }
+                MessageType::Piece | MessageType::CompressedPiece => {
+                    return Err(eyre!("sent a piece of a file outside of a chunk"));
+                }
+                MessageType::Resend => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let resend = cipher.decrypt(&buf, msg_type as u8)?;
+                    if resend.len() != 16 {
+                        return Err(eyre!("sent an invalid request to resend a file"));
+                    }
+                    let id = u64::from_be_bytes(resend[..8].try_into()?);
+                    let offset = u64::from_be_bytes(resend[8..].try_into()?);
+                    tx.send(ResendEvent(connection.peer_addr.clone(), id, offset))?;
+                }
+                MessageType::FileHash => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let file_hash = cipher.decrypt(&buf, msg_type as u8)?;
+                    if file_hash.len() != 40 {
+                        return Err(eyre!("sent an invalid file hash"));
+                    }
+                    let id = u64::from_be_bytes(file_hash[..8].try_into()?);
+                    let hash = blake3::Hash::from_bytes(file_hash[8..].try_into()?);
+                    let mut offers = connection.offers.lock().unwrap();
+                    //so are hashes of cancelled downloads
+                    let Some(received) = offers.get(&id) else {
+                        continue;
+                    };
+                    let Some(part) = received.partial.as_ref().filter(|p| p.started()) else {
+                        return Err(eyre!("sent the hash of a file before starting it"));
+                    };
+                    let complete = part.written == received.offer.size;
+                    let intact = complete && part.hasher.finalize() == hash;
+                    let received = offers.remove(&id).unwrap();
+                    drop(offers);
+                    if intact {
+                        finish_download(tx, connection, &download_dir, received)?;
+                        continue;
+                    }
+                    tx.send(DownloadCompleteEvent(received.download_id))?;
+                    //pieces still missing after a resend the peer didn't get to can be resumed,
+                    //a wrong hash means some of what's there is corrupted
+                    tx.send(ErrorEvent(if complete {
+                        format!(
+                            "Discarded \"{}\" from {}, it doesn't match the peer's hash",
+                            received.offer.name, connection.peer_addr
+                        )
+                    } else {
+                        format!(
+                            "Stopped receiving \"{}\" from {}, it arrived incomplete",
+                            received.offer.name, connection.peer_addr
+                        )
+                    }))?;
+                    received.cancel(complete)?;
+                }
+                MessageType::Ping => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let ping = cipher.decrypt(&buf, msg_type as u8)?;
+                    if ping.len() != 8 {
+                        return Err(eyre!("sent an invalid ping"));
+                    }
+                    //dropped if too many answers are waiting already
+                    let _ = pong_tx.try_send(ping);
+                }
+                MessageType::Pong => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let pong = cipher.decrypt(&buf, msg_type as u8)?;
+                    if pong.len() != 8 {
+                        return Err(eyre!("sent an invalid answer to a ping"));
+                    }
+                    let id = u64::from_be_bytes(pong[..].try_into()?);
+                    let mut pings = connection.pings.lock().unwrap();
+                    //answers to pings that already timed out are ignored
+                    if let Some((sent, requested)) = pings.sent.remove(&id) {
+                        let rtt = sent.elapsed();
+                        pings.rtt = Some(rtt);
+                        drop(pings);
+                        tx.send(PongEvent(connection.peer_addr.clone(), rtt, requested))?;
+                    }
+                }
+                MessageType::Control => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    let control = cipher.decrypt(&buf, msg_type as u8)?;
+                    if control.len() != 10 {
+                        return Err(eyre!("sent an invalid transfer control message"));
+                    }
+                    let id = u64::from_be_bytes(control[..8].try_into()?);
+                    let from_uploader = control[8] == 1;
+                    let action = TransferAction::try_from(control[9])
+                        .map_err(|_| eyre!("sent an invalid transfer control message"))?;
+                    let cancelled = matches!(
+                        action, TransferAction::Cancel | TransferAction::Discard
+                    );
+                    if from_uploader && cancelled {
+                        let received = connection.offers.lock().unwrap().remove(&id);
+                        if let Some(received) = received {
+                            received.cancel(action == TransferAction::Discard)?;
                         }
                     }
+                    tx.send(TransferControlEvent(
+                        connection.peer_addr.clone(), id, !from_uploader, action
+                    ))?;
+                }
+                MessageType::Hello => {
+                    read_frame(&mut reader, &mut buf, &header, max_message_size)?;
+                    return Err(eyre!("sent a second hello"));
                 }
-                _ => ()
             }
         }
     }
@@ -246,81 +1250,582 @@ pub(crate) fn connection_handler(
     Ok(())
 }
 
-///Locks `connection.send_lock`, encrypts `msg`,
+///Checks `piece` against its hash and writes it to its download,
+///asks the peer to send it again if it's corrupted
+///
+///Pieces are [`MessageType::CompressedPiece`]s if there's a `decompressor` for them
+fn receive_piece(
+    tx: &Sender<AppEvent>,
+    connection: &Connection,
+    download_dir: &Path,
+    piece: &[u8],
+    decompressor: Option<&mut PieceDecompressor>
+) -> Result<()> {
+    if piece.len() < PIECE_HEADER_SIZE as usize {
+        return Err(eyre!("sent an invalid piece of a file"));
+    }
+    let (header, data) = piece.split_at(PIECE_HEADER_SIZE as usize);
+    let id = u64::from_be_bytes(header[..8].try_into()?);
+    let offset = u64::from_be_bytes(header[8..16].try_into()?);
+    let hash = blake3::Hash::from_bytes(header[16..].try_into()?);
+    let data = match decompressor {
+        //data that doesn't decompress is corrupted like any other
+        Some(d) => d.decompress(data),
+        None => Some(data)
+    };
+    let data = data.filter(|d| blake3::hash(d) == hash);
+
+    let mut offers = connection.offers.lock().unwrap();
+    //pieces already sent when a download is cancelled are dropped
+    let Some(received) = offers.get_mut(&id) else {
+        return Ok(());
+    };
+    let size = received.offer.size;
+    let Some(part) = received.partial.as_mut().filter(|p| p.started()) else {
+        return Err(eyre!("sent part of a file before starting it"));
+    };
+    //so are pieces sent after a corrupted one, before the peer went back to resend it
+    if offset != part.written {
+        return Ok(());
+    }
+    let Some(data) = data else {
+        received.retries += 1;
+        if received.retries <= MAX_PIECE_RETRIES {
+            drop(offers);
+            return send_resend(connection, id, offset);
+        }
+        //something keeps corrupting the file, keep what arrived intact to resume from later
+        let received = offers.remove(&id).unwrap();
+        drop(offers);
+        tx.send(DownloadCompleteEvent(received.download_id))?;
+        tx.send(ErrorEvent(format!(
+            "Stopped receiving \"{}\" from {}, it keeps arriving corrupted",
+            received.offer.name, connection.peer_addr
+        )))?;
+        received.cancel(false)?;
+        return send_control(connection, id, false, TransferAction::Cancel);
+    };
+    received.retries = 0;
+    if part.written + data.len() as u64 > size {
+        return Err(eyre!("sent more of \"{}\" than offered", received.offer.name));
+    }
+    part.write(data)?;
+    let progress = part.written;
+    //with a hash to check, the download only ends once it arrives
+    if progress == size && !connection.supports(CAPABILITY_FILE_HASH) {
+        let received = offers.remove(&id).unwrap();
+        drop(offers);
+        finish_download(tx, connection, download_dir, received)?;
+    } else if received.last_progress.elapsed() >= PROGRESS_INTERVAL {
+        tx.send(DownloadProgressEvent(received.download_id, progress))?;
+        received.last_progress = Instant::now();
+    }
+
+    Ok(())
+}
+
+///Decompresses [`MessageType::CompressedPiece`]s into the same buffer every time
+struct PieceDecompressor {
+    decompressor: zstd::bulk::Decompressor<'static>,
+    buffer: Vec<u8>
+}
+
+impl PieceDecompressor {
+    fn new() -> Result<Self> {
+        Ok(Self { decompressor: zstd::bulk::Decompressor::new()?, buffer: vec![] })
+    }
+
+    ///Returns `data` decompressed, or [`None`] if it doesn't decompress
+    ///to the size it says it does, or that's bigger than [`MAX_PIECE_SIZE`]
+    fn decompress(&mut self, data: &[u8]) -> Option<&[u8]> {
+        let size = zstd::zstd_safe::get_frame_content_size(data).ok().flatten()?;
+        if size > MAX_PIECE_SIZE {
+            return None;
+        }
+        self.buffer.clear();
+        self.buffer.reserve(size as usize);
+        let len = self.decompressor.decompress_to_buffer(data, &mut self.buffer).ok()?;
+
+        (len as u64 == size).then_some(&self.buffer[..])
+    }
+}
+
+///Renames a completed download to its real name and lets the app know
+fn finish_download(
+    tx: &Sender<AppEvent>,
+    connection: &Connection,
+    download_dir: &Path,
+    received: ReceivedOffer
+) -> Result<()> {
+    let ReceivedOffer { download_id, offer, partial, .. } = received;
+    let Some(part) = partial else {
+        return Ok(());
+    };
+    let path = part.finish(download_dir)?;
+    tx.send(DownloadCompleteEvent(download_id))?;
+    tx.send(FileReceivedEvent(connection.peer_addr.clone(), offer.id, PathBuf::from(path)))?;
+    let kind = if offer.is_dir() { "directory" } else { "file" };
+    let mut message = Line::from(Span::styled(
+        format!("Received {kind} \"{}\" from ", offer.name), INFO
+    ));
+    let peer = connection.display_peer(false).spans;
+    message.spans.extend(peer);
+    tx.send(MessageEvent(message))?;
+
+    Ok(())
+}
+
+///Sends `hello` over `stream` and returns the peer's [`Hello`],
+///returns an error if the peer is running an incompatible version
+///or didn't sign the handshake with the identity key it sent
+fn exchange_hello(
+    stream: &TcpStream,
+    handshake: &Handshake,
+    (send_cipher, recv_cipher): (&mut CipherState, &mut CipherState),
+    hello: &Hello,
+    max_size: u64
+) -> Result<Hello> {
+    let mut writer = BufWriter::new(stream);
+    write_frame(&mut writer, send_cipher, &hello.to_bytes(), MessageType::Hello as u8)?;
+    writer.flush()?;
+
+    let mut reader = stream;
+    let mut header = [0u8; 8];
+    let mut buf = vec![];
+    //clients before the handshake existed start with their nick or a message instead
+    reader.read_exact(&mut header).map_err(|_| eyre!(
+        "no handshake received, the peer is probably running an older version"
+    ))?;
+    if header[0] != MessageType::Hello as u8 {
+        return Err(eyre!("no handshake received, the peer is probably running an older version"));
+    }
+    read_frame(&mut reader, &mut buf, &header, max_size)?;
+    let peer_hello = Hello::from_bytes(
+        &recv_cipher.decrypt(&buf, MessageType::Hello as u8).map_err(|_| eyre!(
+            "failed to decrypt the handshake, \
+            the peer may be running an incompatible version or using a different passphrase"
+        ))?
+    )?;
+    if peer_hello.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(eyre!(
+            "the peer is running version {} which is too old, protocol {} < {}",
+            peer_hello.client_version, peer_hello.protocol_version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    verify_handshake(&peer_hello.identity_key, &peer_hello.signature, handshake)?;
+
+    Ok(peer_hello)
+}
+
+///Reads a frame with the length given in `header` into `buf`,
+///without allocating for it if it's larger than `max_size`
+fn read_frame(
+    reader: &mut impl Read,
+    buf: &mut Vec<u8>,
+    header: &[u8; 8],
+    max_size: u64
+) -> Result<()> {
+    let mut h = *header;
+    h[0] = 0;
+    let size = u64::from_be_bytes(h);
+    if size > max_size {
+        return Err(eyre!(
+            "sent a message of {}, over the {} limit",
+            Size::from_bytes(size), Size::from_bytes(max_size)
+        ));
+    }
+    buf.resize(size as usize, 0);
+    reader.read_exact(buf)?;
+
+    Ok(())
+}
+
+///Reads a frame of a type from a newer client and drops it,
+///it's still decrypted so the next frame is expected with the right counter
+fn skip_frame(
+    reader: &mut impl Read,
+    buf: &mut Vec<u8>,
+    header: &[u8; 8],
+    cipher: &mut CipherState,
+    max_size: u64
+) -> Result<()> {
+    read_frame(reader, buf, header, max_size)?;
+    cipher.decrypt(buf, header[0])?;
+
+    Ok(())
+}
+
+///Locks `connection.send_cipher`, encrypts `msg`,
 ///and sends the encrypted message with a header of `msg_type` to `connection.stream`
 pub(crate) fn send_msg(
     connection: Arc<Connection>,
     msg: Arc<String>,
     msg_type: &MessageType
+) -> Result<()> {
+    send_frame(&connection, msg.as_bytes(), msg_type)
+}
+
+///Locks `connection.send_cipher`, encrypts `payload`,
+///and sends it with a header of `msg_type` to `connection.stream`
+///ahead of any chunks of files waiting to be sent
+fn send_frame(connection: &Connection, payload: &[u8], msg_type: &MessageType) -> Result<()> {
+    connection.scheduler.control(|| write_to_connection(connection, payload, msg_type))
+}
+
+///Sends `piece`, a [`MessageType::Piece`] or [`MessageType::CompressedPiece`]
+///of the transfer `id`, as [`MessageType::Chunk`]s, each one waiting for its turn, see [`Scheduler`]
+///
+///`chunk` is reused for every chunk to avoid allocating them
+fn send_piece(
+    connection: &Connection,
+    id: u64,
+    piece: &[u8],
+    msg_type: MessageType,
+    chunk: &mut Vec<u8>
+) -> Result<()> {
+    connection.scheduler.piece(MAX_PIECES_IN_PROGRESS, || {
+        let mut data = piece.chunks(CHUNK_SIZE as usize).peekable();
+        while let Some(d) = data.next() {
+            chunk.clear();
+            chunk.extend_from_slice(&id.to_be_bytes());
+            chunk.push(msg_type as u8);
+            chunk.push(data.peek().is_none() as u8);
+            chunk.extend_from_slice(d);
+            connection.global_rate_limits.upload.throttle(chunk.len() as u64);
+            connection.rate_limits.upload.throttle(chunk.len() as u64);
+            connection.scheduler.chunk(id, || {
+                write_to_connection(connection, chunk, &MessageType::Chunk)
+            })?;
+        }
+
+        Ok(())
+    })
+}
+
+///Encrypts `payload` and writes it with a header of `msg_type` to `connection.stream`
+fn write_to_connection(
+    connection: &Connection,
+    payload: &[u8],
+    msg_type: &MessageType
 ) -> Result<()> {
     let mut writer = BufWriter::new(&connection.stream);
-    let encrypted = encrypt(msg.as_bytes(), &connection.secret)?;
-    let header = generate_header(&encrypted, msg_type);
-    let lock = connection.send_lock.lock();
+    //encrypt while holding the lock so messages are sent in counter order
+    let mut cipher = connection.send_cipher.lock().unwrap();
+    write_frame(&mut writer, &mut cipher, payload, *msg_type as u8)?;
+    writer.flush()?;
+    drop(cipher);
+
+    Ok(())
+}
+
+///Encrypts `payload` with `cipher` and writes it to `writer` with a header of `msg_type`,
+///replacing the key first if it's due
+fn write_frame(
+    writer: &mut impl Write,
+    cipher: &mut CipherState,
+    payload: &[u8],
+    msg_type: u8
+) -> Result<()> {
+    if cipher.needs_rekey() {
+        write_frame_with_key(writer, cipher, &[], MessageType::Rekey as u8)?;
+        cipher.ratchet()?;
+    }
+    write_frame_with_key(writer, cipher, payload, msg_type)
+}
+
+///Encrypts `payload` with the current key of `cipher`
+///and writes it to `writer` with a header of `msg_type`
+fn write_frame_with_key(
+    writer: &mut impl Write,
+    cipher: &mut CipherState,
+    payload: &[u8],
+    msg_type: u8
+) -> Result<()> {
+    let encrypted = cipher.encrypt(payload, msg_type)?;
+    let mut header: [u8; 8] = (encrypted.len() as u64).to_be_bytes();
+    header[0] = msg_type;
     writer.write_all(&header)?;
     writer.write_all(&encrypted)?;
-    writer.flush()?;
-    drop(lock);
 
     Ok(())
 }
 
-pub(crate) fn send_file(connection: Arc<Connection>, path: Arc<PathBuf>) -> Result<()> {
-    let file = fs::File::open(path.as_path())?;
-    let mut buffer = Vec::with_capacity(PIECE_SIZE as usize);
-    let mut stream_writer = BufWriter::new(&connection.stream);
-    let file_reader = &mut BufReader::new(&file);
-
-    let file_size = file.metadata()?.len();
-    let mut header = file_size.to_be_bytes().to_vec();
-    header[0] = 254;
-    //acquire lock before first write
-    let lock = connection.send_lock.lock();
-    stream_writer.write_all(&header)?;
-
-    //calculate, encrypt, and send CRC-32 checksum
-    let crc = checksum_file(Crc32IsoHdlc, path.to_str().unwrap(), None)?;
-    let enc_crc = encrypt(&crc.to_be_bytes(), &connection.secret)?;
-    header = Vec::from(generate_header(&enc_crc, &MessageType::Text));
-    stream_writer.write_all(&header)?;
-    stream_writer.write_all(&enc_crc)?;
-
-    //get, encrypt, and send file name
-    let name = path.file_name().unwrap().to_str().unwrap();
-    let enc_name = encrypt(name.as_bytes(), &connection.secret)?;
-    header = enc_name.len().to_be_bytes().to_vec();
-    stream_writer.write_all(&header)?;
-    stream_writer.write_all(&enc_name)?;
-
-    //encrypt and send each piece
-    let pieces = (file_size + PIECE_SIZE - 1) / PIECE_SIZE;
-    for _piece in 0..pieces {
+///Replaces the key used to send to the peer when it's due, even if nothing is being sent,
+///until the connection is gone
+fn rekey_timer(connection: Weak<Connection>, running: Arc<AtomicBool>) -> Result<()> {
+    while running.load(Ordering::Relaxed) {
+        sleep(REKEY_CHECK_INTERVAL);
+        let Some(connection) = connection.upgrade() else {
+            break;
+        };
+        let mut cipher = connection.send_cipher.lock().unwrap();
+        if cipher.needs_rekey() {
+            let mut writer = BufWriter::new(&connection.stream);
+            write_frame_with_key(&mut writer, &mut cipher, &[], MessageType::Rekey as u8)?;
+            writer.flush()?;
+            cipher.ratchet()?;
+        }
+    }
+
+    Ok(())
+}
+
+///Pings the peer every [`PING_INTERVAL`] until the connection is gone,
+///and drops the connection if a ping is still unanswered and nothing arrived from the peer
+///for [`PING_TIMEOUT`], like when the peer's computer went to sleep
+fn keepalive(
+    tx: Sender<AppEvent>,
+    connection: Weak<Connection>,
+    running: Arc<AtomicBool>
+) -> Result<()> {
+    while running.load(Ordering::Relaxed) {
+        sleep(PING_INTERVAL);
+        let Some(connection) = connection.upgrade() else {
+            break;
+        };
+        let timed_out = {
+            let mut pings = connection.pings.lock().unwrap();
+            let timed_out = pings.last_received.elapsed() > PING_TIMEOUT && !pings.sent.is_empty();
+            //the peer is still sending, so it's only busy, stop waiting for these
+            pings.sent.retain(|_, (sent, _)| sent.elapsed() <= PING_TIMEOUT);
+            timed_out
+        };
+        if timed_out {
+            //makes the thread reading from the peer stop and report the disconnection
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            tx.send(ErrorEvent(format!(
+                "Dropped connection to {}, no answer for {} seconds",
+                connection.peer_addr, PING_TIMEOUT.as_secs()
+            )))?;
+            break;
+        }
+        if connection.ping(false).is_err() {
+            //the write timed out or the connection is already closed
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+///Sends `offer`, see [`Offer::new`]
+pub(crate) fn send_offer(connection: Arc<Connection>, offer: &Offer) -> Result<()> {
+    send_frame(&connection, &offer.to_bytes(), &MessageType::Offer)
+}
+
+///Sends `preview` for the image offered to the peer with the given transfer `id`
+pub(crate) fn send_image(connection: &Connection, preview: &ImagePreview, id: u64) -> Result<()> {
+    send_frame(connection, &preview.to_bytes(id), &MessageType::Image)
+}
+
+///Accepts the peer's offer with the given transfer `id` starting at `offset` if one is given,
+///otherwise rejects it
+pub(crate) fn send_answer(connection: Arc<Connection>, id: u64, offset: Option<u64>) -> Result<()> {
+    let mut answer = id.to_be_bytes().to_vec();
+    answer.push(offset.is_some() as u8);
+    answer.extend_from_slice(&offset.unwrap_or(0).to_be_bytes());
+    send_frame(&connection, &answer, &MessageType::Answer)
+}
+
+///Pauses, resumes or cancels the transfer `id`,
+///`upload` is whether it's a file we're sending or one the peer is sending
+pub(crate) fn send_control(
+    connection: &Connection,
+    id: u64,
+    upload: bool,
+    action: TransferAction
+) -> Result<()> {
+    let mut control = id.to_be_bytes().to_vec();
+    control.push(upload as u8);
+    control.push(action as u8);
+    send_frame(connection, &control, &MessageType::Control)
+}
+
+///Tells the peer we're disconnecting with an optional `reason`, then stops sending to it
+///
+///Only the sending side is shut down, so the peer can still read everything sent before
+///and close the connection itself, unless the goodbye isn't sent within [`GOODBYE_TIMEOUT`]
+pub(crate) fn send_goodbye(connection: Arc<Connection>, reason: Option<String>) -> Result<()> {
+    let (sent_tx, sent_rx) = channel();
+    let c = connection.clone();
+    spawn(move || {
+        let reason = reason.unwrap_or_default();
+        sent_tx.send(send_frame(&c, reason.as_bytes(), &MessageType::Goodbye).is_ok())
+    });
+    //a peer that stopped reading, or a chunk stuck ahead of the goodbye, doesn't hold it up,
+    //shutting the stream down also fails the write waiting for it
+    if sent_rx.recv_timeout(GOODBYE_TIMEOUT).unwrap_or(false) {
+        connection.stream.shutdown(Shutdown::Write)?;
+    } else {
+        connection.stream.shutdown(Shutdown::Both)?;
+    }
+
+    Ok(())
+}
+
+///Asks the peer to send the file `id` again from `offset`
+fn send_resend(connection: &Connection, id: u64, offset: u64) -> Result<()> {
+    let mut resend = id.to_be_bytes().to_vec();
+    resend.extend_from_slice(&offset.to_be_bytes());
+    send_frame(connection, &resend, &MessageType::Resend)
+}
+
+///Error sending to the peer in [`send_file`], as opposed to one reading the files,
+///an upload that stopped because of it can resume once the peer reconnects
+#[derive(Debug)]
+pub(crate) struct StreamError(color_eyre::Report);
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+///Sends the files in `entries` under `path` one after another as the accepted transfer `id`,
+///skipping the first `offset` bytes the peer already has,
+///then the hash of all of them if the peer checks it
+///
+///The lock on the connection is only held for one chunk at a time,
+///so other messages can still be sent and the upload can be paused without blocking them,
+///and the same buffers are used for every piece so memory use doesn't grow with the file
+pub(crate) fn send_file(
+    tx: Sender<AppEvent>,
+    connection: Arc<Connection>,
+    path: Arc<PathBuf>,
+    entries: Arc<Vec<TreeEntry>>,
+    id: u64,
+    offset: u64,
+    control: Arc<UploadControl>
+) -> Result<()> {
+    let size: u64 = entries.iter().map(|e| e.size).sum();
+    if offset > size {
+        return Err(eyre!("Peer asked for \"{}\" from past the end", path.display()));
+    }
+    let mut piece_size = MIN_PIECE_SIZE.min(connection.max_piece_size);
+    let mut buffer = vec![];
+    let mut compressed = vec![];
+    let mut chunk = Vec::with_capacity((CHUNK_SIZE + CHUNK_HEADER_SIZE) as usize);
+    let mut file_reader = TreeReader::new(&path, &entries, offset)?;
+    //the peer checks the whole file, including what it already had
+    let mut hasher = connection.supports(CAPABILITY_FILE_HASH)
+        .then(|| hash_prefix(&path, &entries, offset))
+        .transpose()?;
+
+    let mut file_header = id.to_be_bytes().to_vec();
+    file_header.extend_from_slice(&offset.to_be_bytes());
+    send_frame(&connection, &file_header, &MessageType::File).map_err(StreamError)?;
+
+    //hash, encrypt and send each piece, compressed if the peer can decompress it
+    let mut compressor = connection.supports(CAPABILITY_ZSTD)
+        .then(|| zstd::bulk::Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL))
+        .transpose()?;
+    let mut progress = offset;
+    let mut last = Instant::now();
+    while progress < size {
+        while control.paused.load(Ordering::Relaxed) && !control.cancelled.load(Ordering::Relaxed) {
+            sleep(PAUSE_POLL_INTERVAL);
+        }
+        if control.cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        if let Some(from) = control.resend_from.lock().unwrap().take() {
+            //from before `offset` if the peer had to start over
+            if from > progress {
+                return Err(eyre!(
+                    "Peer asked to resend \"{}\" from an invalid offset", path.display()
+                ));
+            }
+            file_reader = TreeReader::new(&path, &entries, from)?;
+            if hasher.is_some() {
+                hasher = Some(hash_prefix(&path, &entries, from)?);
+            }
+            progress = from;
+        }
+        let started = Instant::now();
         buffer.clear();
-        file_reader.take(PIECE_SIZE).read_to_end(&mut buffer)?;
-        let e = encrypt(&buffer, &connection.secret)?;
-        header = e.len().to_be_bytes().to_vec();
-        stream_writer.write_all(&header)?;
-        stream_writer.write_all(&e)?;
+        buffer.reserve((piece_size + PIECE_HEADER_SIZE) as usize);
+        buffer.extend_from_slice(&id.to_be_bytes());
+        buffer.extend_from_slice(&progress.to_be_bytes());
+        buffer.extend_from_slice(&[0; 32]);
+        (&mut file_reader).take(piece_size).read_to_end(&mut buffer)?;
+        let len = buffer.len() as u64 - PIECE_HEADER_SIZE;
+        if len == 0 {
+            return Err(eyre!("\"{}\" got shorter while sending it", path.display()));
+        }
+        let hash = blake3::hash(&buffer[PIECE_HEADER_SIZE as usize..]);
+        if let Some(h) = hasher.as_mut() {
+            h.update(&buffer[PIECE_HEADER_SIZE as usize..]);
+        }
+        buffer[16..PIECE_HEADER_SIZE as usize].copy_from_slice(hash.as_bytes());
+        let shrunk = match compressor.as_mut() {
+            Some(c) => compress_piece(c, &buffer, &mut compressed)?,
+            None => false
+        };
+        //data that starts out incompressible likely stays that way
+        if !shrunk && progress == offset {
+            compressor = None;
+        }
+        let (piece, msg_type) = if shrunk {
+            (&compressed, MessageType::CompressedPiece)
+        } else {
+            (&buffer, MessageType::Piece)
+        };
+        send_piece(&connection, id, piece, msg_type, &mut chunk).map_err(StreamError)?;
+        progress += len;
+        //bigger pieces have less overhead, smaller ones keep a slow or throttled upload responsive
+        let elapsed = started.elapsed();
+        if elapsed < PIECE_TARGET_TIME / 2 {
+            piece_size = (piece_size * 2).min(connection.max_piece_size);
+        } else if elapsed > PIECE_TARGET_TIME * 2 {
+            piece_size = (piece_size / 2).max(MIN_PIECE_SIZE);
+        }
+        if last.elapsed() >= PROGRESS_INTERVAL {
+            tx.send(UploadProgressEvent(connection.peer_addr.clone(), id, progress))?;
+            last = Instant::now();
+        }
+    }
+    if let Some(h) = hasher.filter(|_| progress == size) {
+        let mut file_hash = id.to_be_bytes().to_vec();
+        file_hash.extend_from_slice(h.finalize().as_bytes());
+        send_frame(&connection, &file_hash, &MessageType::FileHash).map_err(StreamError)?;
     }
-    stream_writer.flush()?;
-    drop(lock);
 
     Ok(())
 }
 
-fn generate_header(msg: &Vec<u8>, msg_type: &MessageType) -> [u8; 8] {
-    let mut header: [u8; 8] = msg.len().to_be_bytes();
-    header[0] = *msg_type as u8;
-    header
+///Compresses the data of `piece`, a [`MessageType::Piece`] message, into `compressed`,
+///returns whether it shrunk by at least a tenth
+fn compress_piece(
+    compressor: &mut zstd::bulk::Compressor,
+    piece: &[u8],
+    compressed: &mut Vec<u8>
+) -> Result<bool> {
+    let (header, data) = piece.split_at(PIECE_HEADER_SIZE as usize);
+    compressed.clear();
+    compressed.extend_from_slice(header);
+    compressed.reserve(zstd::zstd_safe::compress_bound(data.len()));
+    //compress into the space after the header
+    let mut cursor = Cursor::new(&mut *compressed);
+    cursor.set_position(PIECE_HEADER_SIZE);
+    let len = compressor.compress_to_buffer(data, &mut cursor)?;
+
+    Ok((data.len() - len.min(data.len())) * 10 >= data.len())
 }
 
-///Returns a vector of all IPv4 addresses on the local machine
-pub(crate) fn local_ipv4_addrs() -> Vec<String> {
+///Returns a vector of all IPv4 and IPv6 addresses on the local machine,
+///link-local IPv6 addresses with the index of their interface as the scope id
+pub(crate) fn local_ip_addrs() -> Vec<String> {
     let mut ips = vec![];
     for iface in datalink::interfaces() {
         for ip in iface.ips {
-            if let IpNetwork::V4(_) = ip {
-                ips.push(ip.ip().to_string());
+            match ip.ip() {
+                IpAddr::V6(v6) if v6.is_unicast_link_local() => {
+                    ips.push(format!("{v6}%{}", iface.index));
+                }
+                ip => ips.push(ip.to_string())
             }
         }
     }
@@ -328,29 +1833,702 @@ pub(crate) fn local_ipv4_addrs() -> Vec<String> {
     ips
 }
 
-///Attempts to create file_name, renames it if it already exists,
-///and returns the file and new name if successful
-fn try_create_file(file_name: &str) -> Option<(fs::File, String)> {
-    let path = Path::new(file_name);
-    if let Ok(f) = fs::File::create_new(&path) {
-        Some((f, path.to_str().unwrap().to_string()))
+///Joins `ip` and `port` into an address, putting IPv6 addresses in brackets
+pub(crate) fn join_ip_port(ip: &str, port: u16) -> String {
+    if ip.contains(':') && !ip.starts_with('[') {
+        format!("[{ip}]:{port}")
     } else {
+        format!("{ip}:{port}")
+    }
+}
+
+///Resolves an address like `1.2.3.4:5`, `[::1]:5`, `[fe80::1%eth0]:5` or `host:5`,
+///scope ids of link-local IPv6 addresses can be interface names or indexes
+pub(crate) fn resolve_addr(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    //std only understands scope ids that are indexes
+    if let Some((ip, port)) = addr.strip_prefix('[').and_then(|a| a.split_once("]:"))
+        && let Some((ip, scope)) = ip.split_once('%')
+        && scope.parse::<u32>().is_err() {
+        let index = datalink::interfaces().into_iter()
+            .find(|i| i.name == scope)
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound, format!("no network interface named {scope}")
+            ))?
+            .index;
+        return format!("[{ip}%{index}]:{port}").to_socket_addrs().map(Iterator::collect);
+    }
+
+    addr.to_socket_addrs().map(Iterator::collect)
+}
+
+///Binds a [`TcpListener`] to `addr`, one on the unspecified IPv6 address `::`
+///also accepts IPv4 connections, which not every OS does by default
+fn bind_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    if !addr.is_ipv6() || !addr.ip().is_unspecified() {
+        return TcpListener::bind(addr);
+    }
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+
+    Ok(socket.into())
+}
+
+///Returns `addr` with IPv4 addresses that a dual-stack listener mapped to IPv6 mapped back
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip().to_canonical() {
+        ip @ IpAddr::V4(_) => SocketAddr::new(ip, addr.port()),
+        IpAddr::V6(_) => addr
+    }
+}
+
+///Returns a new id for an upload or download
+pub(crate) fn next_transfer_id() -> u64 {
+    NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+///Reduces a file name supplied by a peer to a single plain path component
+///by dropping directories, `.`/`..`, control characters, and characters or names
+///that are reserved on Windows, returns [`None`] if nothing usable is left
+fn sanitize_file_name(file_name: &str) -> Option<String> {
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL",
+        "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    //keep only the last real component, so "../../.bashrc" becomes ".bashrc"
+    let name = file_name
+        .split(['/', '\\'])
+        .rfind(|c| !c.is_empty() && *c != "." && *c != "..")?;
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    //Windows silently strips trailing dots and spaces
+    let name = name.trim_end_matches(['.', ' ']).trim_start();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    let stem = name.split('.').next().unwrap_or(name);
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return Some(format!("_{name}"));
+    }
+
+    Some(name.to_string())
+}
+
+///Attempts to create `file_name` in `dir` with `create`, renames it if it already exists,
+///and returns what `create` returned and the new path if successful
+///
+///Refuses any name that isn't a plain file name directly inside `dir`
+fn try_create<T>(
+    dir: &Path,
+    file_name: &str,
+    create: impl Fn(PathBuf) -> std::io::Result<T>
+) -> Option<(T, String)> {
+    fs::create_dir_all(dir).ok()?;
+    let dir = dir.canonicalize().ok()?;
+    let in_dir = |path: &Path| {
+        path.parent() == Some(dir.as_path())
+            && matches!(path.components().next_back(), Some(Component::Normal(_)))
+    };
+    let path = dir.join(file_name);
+    if !in_dir(&path) {
+        return None;
+    }
+    if let Ok(f) = create(path.clone()) {
+        Some((f, path.to_str()?.to_string()))
+    } else {
+        //format new names as name_n.ext if the name has an extension
+        let (stem, ext) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+            _ => (file_name, String::new())
+        };
         for n in 1..usize::MAX {
-            //should format new_path as path_n.ext if path has a file extension
-            let p = path.to_str().unwrap();
-            let mut parts = (p, "");
-            let mut separator = "";
-            if !path.file_name().unwrap().to_str().unwrap().rfind('.').unwrap_or(1) == 0
-                && let Some(p) = path.to_str().unwrap().rsplit_once('.') {
-                parts = p;
-                separator = ".";
+            let new_path = dir.join(format!("{stem}_{n}{ext}"));
+            if !in_dir(&new_path) {
+                return None;
             }
-            let new_path = format!("{}_{}{}{}", parts.0, n, separator, parts.1);
-            if let Ok(f) = fs::File::create_new(new_path.clone()) {
-                return Some((f, new_path));
+            if let Ok(f) = create(new_path.clone()) {
+                return Some((f, new_path.to_str()?.to_string()));
             }
         }
 
         None
     }
 }
+
+///Progress of a download, saved next to its part file so it can be resumed if interrupted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PartManifest {
+    ///Fingerprint of the peer sending the file
+    pub(crate) fingerprint: String,
+    ///Name as offered by the peer
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    ///Bytes written to the part file and synced to disk
+    pub(crate) received: u64,
+    ///Files and directories as offered by the peer
+    pub(crate) entries: Vec<TreeEntry>
+}
+
+///Download being written to a `.part` file or directory, renamed to the real name once complete
+#[derive(Debug)]
+pub(crate) struct PartialDownload {
+    pub(crate) path: PathBuf,
+    pub(crate) manifest: PartManifest,
+    ///Open once the peer starts sending
+    writer: Option<TreeWriter>,
+    ///Bytes written to the part file, including ones not synced yet
+    written: u64,
+    ///Hash of the bytes written, checked against the [`MessageType::FileHash`]
+    hasher: blake3::Hasher,
+    last_sync: Instant
+}
+
+impl PartialDownload {
+    ///Creates a part file or directory and manifest in `dir` for `offer`
+    ///from the peer with `fingerprint`, returns [`None`] if the offered name isn't usable
+    fn create(dir: &Path, fingerprint: &str, offer: Offer) -> Option<Self> {
+        let name = format!("{}.part", sanitize_file_name(&offer.name)?);
+        let path = if offer.is_dir() {
+            try_create(dir, &name, fs::create_dir)?.1
+        } else {
+            try_create(dir, &name, fs::File::create_new)?.1
+        };
+        let path = PathBuf::from(path);
+        let part = Self {
+            writer: Some(TreeWriter::open(&path, &offer.entries, 0).ok()?),
+            path,
+            manifest: PartManifest {
+                fingerprint: fingerprint.to_string(),
+                name: offer.name,
+                size: offer.size,
+                received: 0,
+                entries: offer.entries
+            },
+            written: 0,
+            hasher: blake3::Hasher::new(),
+            last_sync: Instant::now()
+        };
+        part.save().ok()?;
+
+        Some(part)
+    }
+
+    ///Looks in `dir` for an interrupted download of `offer` from the peer with `fingerprint`
+    fn find(dir: &Path, fingerprint: &str, offer: &Offer) -> Option<Self> {
+        fs::read_dir(dir).ok()?.flatten().find_map(|entry| {
+            let manifest_path = entry.path();
+            let path = PathBuf::from(manifest_path.to_str()?.strip_suffix(".part.toml")?)
+                .with_added_extension("part");
+            let manifest: PartManifest = toml::from_str(
+                &fs::read_to_string(&manifest_path).ok()?
+            ).ok()?;
+            let matches = manifest.fingerprint == fingerprint
+                && manifest.name == offer.name
+                && manifest.size == offer.size
+                && manifest.entries == offer.entries;
+            //the part file can't be shorter than what was synced unless something else touched it
+            let intact = if offer.is_dir() {
+                path.is_dir()
+            } else {
+                fs::metadata(&path).ok()?.len() >= manifest.received
+            };
+            (matches && intact).then(|| Self {
+                path,
+                written: manifest.received,
+                manifest,
+                writer: None,
+                hasher: blake3::Hasher::new(),
+                last_sync: Instant::now()
+            })
+        })
+    }
+
+    ///Returns whether the part file is open for writing
+    fn started(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    ///Opens the part file to continue writing after the bytes recorded in the manifest,
+    ///dropping anything written after the last sync
+    ///
+    ///What's already there is hashed again, so the whole file is checked once complete
+    fn open(&mut self) -> Result<()> {
+        let manifest = &self.manifest;
+        self.hasher = hash_prefix(&self.path, &manifest.entries, manifest.received)?;
+        self.writer = Some(TreeWriter::open(&self.path, &manifest.entries, manifest.received)?);
+        self.written = manifest.received;
+
+        Ok(())
+    }
+
+    ///Appends `bytes` to the part file, periodically syncing it and updating the manifest
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let Some(writer) = &mut self.writer else {
+            return Err(eyre!("Part file isn't open"));
+        };
+        writer.write(bytes)?;
+        self.hasher.update(bytes);
+        self.written += bytes.len() as u64;
+        if self.last_sync.elapsed() >= PART_SYNC_INTERVAL {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    ///Flushes the part file to disk and records how much of it is there in the manifest,
+    ///so resuming never skips data that was lost
+    fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.sync()?;
+            self.manifest.received = self.written;
+            self.save()?;
+        }
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    ///Syncs and closes the part file, leaving it and the manifest to resume from later
+    fn close(mut self) -> Result<()> {
+        self.sync()
+    }
+
+    ///Removes the part file and manifest
+    fn discard(self) -> Result<()> {
+        drop(self.writer);
+        if self.path.is_dir() {
+            fs::remove_dir_all(&self.path)?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        fs::remove_file(manifest_path(&self.path))?;
+
+        Ok(())
+    }
+
+    ///Writes the manifest next to the part file
+    fn save(&self) -> Result<()> {
+        //write to a temporary file first so an interruption can't leave a truncated manifest
+        let mut tmp = OsString::from(manifest_path(&self.path));
+        tmp.push(".tmp");
+        fs::write(&tmp, toml::to_string(&self.manifest)?)?;
+        fs::rename(&tmp, manifest_path(&self.path))?;
+
+        Ok(())
+    }
+
+    ///Removes the manifest and renames the part file to a free name in `dir`,
+    ///returns the new path
+    fn finish(mut self, dir: &Path) -> Result<String> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        fs::remove_file(manifest_path(&self.path))?;
+        let name = sanitize_file_name(&self.manifest.name)
+            .ok_or_else(|| eyre!("Invalid file name"))?;
+        //reserve the name so nothing else takes it before the rename
+        let path = if self.path.is_dir() {
+            let (_, path) = try_create(dir, &name, fs::create_dir)
+                .ok_or_else(|| eyre!("Failed to create \"{name}\""))?;
+            //not every platform can rename a directory over an empty one
+            fs::remove_dir(&path)?;
+            path
+        } else {
+            try_create(dir, &name, fs::File::create_new)
+                .ok_or_else(|| eyre!("Failed to create \"{name}\""))?.1
+        };
+        fs::rename(&self.path, &path)?;
+
+        Ok(path)
+    }
+}
+
+///Returns the path of the [`PartManifest`] for the part file at `path`
+fn manifest_path(path: &Path) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(".toml");
+    PathBuf::from(path)
+}
+
+///Lists the file or directory at `path` and, for a directory, everything under it
+///in the order it's sent, skipping symbolic links inside it
+pub(crate) fn read_tree(path: &Path) -> Result<Vec<TreeEntry>> {
+    let mut entries = vec![];
+    add_tree_entries(path, String::new(), fs::metadata(path)?, &mut entries)?;
+
+    Ok(entries)
+}
+
+///Adds an entry for `path` to `entries`, followed by everything under it if it's a directory
+fn add_tree_entries(
+    path: &Path,
+    relative: String,
+    metadata: fs::Metadata,
+    entries: &mut Vec<TreeEntry>
+) -> Result<()> {
+    let modified = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let dir = metadata.is_dir();
+    entries.push(TreeEntry {
+        path: relative.clone(),
+        size: if dir { 0 } else { metadata.len() },
+        modified,
+        dir
+    });
+    if dir {
+        let mut children = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let metadata = child.metadata()?;
+            if metadata.is_symlink() {
+                continue;
+            }
+            let name = child.file_name().into_string()
+                .map_err(|n| eyre!("\"{}\" isn't valid UTF-8", n.display()))?;
+            let relative = if relative.is_empty() { name } else { format!("{relative}/{name}") };
+            add_tree_entries(&child.path(), relative, metadata, entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+///Returns where `entry` goes under `root`, the file or directory the tree was offered as,
+///cleaning up each component of its path like [`sanitize_file_name`]
+fn entry_path(root: &Path, entry: &TreeEntry) -> Option<PathBuf> {
+    if entry.path.is_empty() {
+        return Some(root.to_path_buf());
+    }
+    entry.path.split('/').try_fold(root.to_path_buf(), |path, c| {
+        Some(path.join(sanitize_file_name(c)?))
+    })
+}
+
+///Sets the modification time of the file or directory at `path`
+///to `modified` seconds since the Unix epoch
+fn set_modified(path: &Path, modified: u64, dir: bool) -> std::io::Result<()> {
+    let file = fs::OpenOptions::new().read(dir).write(!dir).open(path)?;
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+}
+
+///Returns a hasher fed the first `len` bytes of the files in `entries` under `root`
+fn hash_prefix(root: &Path, entries: &[TreeEntry], len: u64) -> Result<blake3::Hasher> {
+    let mut hasher = blake3::Hasher::new();
+    let hashed = std::io::copy(&mut TreeReader::new(root, entries, 0)?.take(len), &mut hasher)?;
+    if hashed != len {
+        return Err(eyre!("Files under \"{}\" are shorter than expected", root.display()));
+    }
+
+    Ok(hasher)
+}
+
+///Reads the files of a tree one after another, as they're sent
+struct TreeReader<'a> {
+    root: &'a Path,
+    ///Files left to read, in reverse order
+    files: Vec<&'a TreeEntry>,
+    file: Option<std::io::Take<BufReader<fs::File>>>
+}
+
+impl<'a> TreeReader<'a> {
+    ///Starts reading the files in `entries` under `root` after the first `offset` bytes
+    fn new(root: &'a Path, entries: &'a [TreeEntry], mut offset: u64) -> Result<Self> {
+        let mut reader = Self {
+            root,
+            files: entries.iter().filter(|e| !e.dir).rev().collect(),
+            file: None
+        };
+        while let Some(entry) = reader.files.pop() {
+            if offset < entry.size {
+                let mut file = reader.open(entry)?;
+                file.get_mut().seek(SeekFrom::Start(offset))?;
+                file.set_limit(entry.size - offset);
+                reader.file = Some(file);
+                break;
+            }
+            offset -= entry.size;
+        }
+
+        Ok(reader)
+    }
+
+    fn open(&self, entry: &TreeEntry) -> std::io::Result<std::io::Take<BufReader<fs::File>>> {
+        let path = entry_path(self.root, entry).ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(BufReader::new(fs::File::open(path)?).take(entry.size))
+    }
+}
+
+impl Read for TreeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.file {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                if file.limit() > 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof, "a file got shorter while sending it"
+                    ));
+                }
+            }
+            let Some(entry) = self.files.pop() else {
+                return Ok(0);
+            };
+            self.file = Some(self.open(entry)?);
+        }
+    }
+}
+
+///Writes the data of a tree into its files one after another, as it's received
+#[derive(Debug)]
+struct TreeWriter {
+    root: PathBuf,
+    entries: Vec<TreeEntry>,
+    ///Index of the entry after the file being written
+    next: usize,
+    ///Bytes left to write to the current file
+    remaining: u64,
+    file: Option<BufWriter<fs::File>>
+}
+
+impl TreeWriter {
+    ///Creates the directories of the tree at `root`, and opens the file where the first
+    ///`offset` bytes end to continue writing, dropping anything written after them
+    fn open(root: &Path, entries: &[TreeEntry], mut offset: u64) -> Result<Self> {
+        let mut writer = Self {
+            root: root.to_path_buf(),
+            entries: entries.to_vec(),
+            next: 0,
+            remaining: 0,
+            file: None
+        };
+        for entry in entries.iter().filter(|e| e.dir) {
+            fs::create_dir_all(writer.path(entry)?)?;
+        }
+        while let Some(entry) = entries.get(writer.next) {
+            writer.next += 1;
+            if entry.dir {
+                continue;
+            }
+            let mut file = fs::OpenOptions::new().create(true).truncate(false).write(true)
+                .open(writer.path(entry)?)?;
+            if offset < entry.size {
+                file.set_len(offset)?;
+                file.seek(SeekFrom::End(0))?;
+                writer.remaining = entry.size - offset;
+                writer.file = Some(BufWriter::new(file));
+                break;
+            }
+            offset -= entry.size;
+        }
+
+        Ok(writer)
+    }
+
+    fn path(&self, entry: &TreeEntry) -> Result<PathBuf> {
+        entry_path(&self.root, entry).ok_or_else(|| eyre!("Invalid path \"{}\"", entry.path))
+    }
+
+    ///Writes `bytes` to the current file, moving on to the next ones as each is filled
+    fn write(&mut self, mut bytes: &[u8]) -> Result<()> {
+        while !bytes.is_empty() {
+            if self.remaining == 0 {
+                self.next_file()?;
+            }
+            let Some(file) = &mut self.file else {
+                return Err(eyre!("Part file isn't open"));
+            };
+            let n = bytes.len().min(self.remaining as usize);
+            file.write_all(&bytes[..n])?;
+            self.remaining -= n as u64;
+            bytes = &bytes[n..];
+        }
+
+        Ok(())
+    }
+
+    ///Syncs the current file and creates the files after it,
+    ///until one that isn't empty is open
+    fn next_file(&mut self) -> Result<()> {
+        self.sync()?;
+        self.file = None;
+        while let Some(entry) = self.entries.get(self.next) {
+            self.next += 1;
+            if entry.dir {
+                continue;
+            }
+            let file = fs::File::create(self.path(entry)?)?;
+            if entry.size > 0 {
+                self.remaining = entry.size;
+                self.file = Some(BufWriter::new(file));
+                return Ok(());
+            }
+        }
+
+        Err(eyre!("More data received than offered"))
+    }
+
+    ///Flushes the current file to disk
+    fn sync(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+            file.get_ref().sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    ///Creates any empty files left at the end of the tree,
+    ///then sets the modification times of everything in it
+    fn finish(mut self) -> Result<()> {
+        self.sync()?;
+        self.file = None;
+        for entry in self.entries[self.next..].iter().filter(|e| !e.dir) {
+            fs::File::create(self.path(entry)?)?;
+        }
+        //contents first, as changing them updates the directory's time
+        for entry in self.entries.iter().rev() {
+            let result = set_modified(&self.path(entry)?, entry.modified, entry.dir);
+            //not every platform can open a directory to set its time
+            if !entry.dir {
+                result?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_file_name_drops_directories() {
+        assert_eq!(sanitize_file_name("../../.bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(sanitize_file_name("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_file_name("dir/..").as_deref(), Some("dir"));
+        assert_eq!(sanitize_file_name("..\\..\\evil.exe").as_deref(), Some("evil.exe"));
+        assert_eq!(sanitize_file_name("C:\\Windows\\win.ini").as_deref(), Some("win.ini"));
+    }
+
+    #[test]
+    fn sanitize_file_name_drops_control_and_reserved_characters() {
+        assert_eq!(sanitize_file_name("a\nb\0c\x1b.txt").as_deref(), Some("abc.txt"));
+        assert_eq!(sanitize_file_name("what?<>|.txt").as_deref(), Some("what____.txt"));
+        assert_eq!(sanitize_file_name("name. . ").as_deref(), Some("name"));
+    }
+
+    #[test]
+    fn sanitize_file_name_escapes_reserved_names() {
+        assert_eq!(sanitize_file_name("CON.txt").as_deref(), Some("_CON.txt"));
+        assert_eq!(sanitize_file_name("lpt1").as_deref(), Some("_lpt1"));
+        assert_eq!(sanitize_file_name("CONSOLE.txt").as_deref(), Some("CONSOLE.txt"));
+    }
+
+    #[test]
+    fn sanitize_file_name_rejects_empty_names() {
+        for name in ["", ".", "..", "...", "/", "\\", "../..", " . ", "\n\t"] {
+            assert_eq!(sanitize_file_name(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn try_create_stays_in_dir_and_renames() {
+        let dir = std::env::temp_dir().join(format!("tcp_messenger_test_{}", std::process::id()));
+        let create = |p: PathBuf| fs::OpenOptions::new().write(true).create_new(true).open(p);
+        let (_, first) = try_create(&dir, "a.txt", create).unwrap();
+        let (_, second) = try_create(&dir, "a.txt", create).unwrap();
+        assert!(first.ends_with("a.txt"));
+        assert!(second.ends_with("a_1.txt"));
+        for name in ["../escape.txt", "/tmp/escape.txt", "..", "sub/file.txt"] {
+            assert!(try_create(&dir, name, create).is_none(), "{name:?}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_prefix_hashes_files_in_order() {
+        let dir = std::env::temp_dir().join(format!("tcp_messenger_hash_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"hello ").unwrap();
+        fs::write(dir.join("b"), b"world").unwrap();
+        let entry = |path: &str, size| TreeEntry {
+            path: path.to_string(), size, modified: 0, dir: false
+        };
+        let entries = [entry("a", 6), entry("b", 5)];
+        let hash = |len| hash_prefix(&dir, &entries, len).map(|h| h.finalize());
+        assert_eq!(hash(11).unwrap(), blake3::hash(b"hello world"));
+        assert_eq!(hash(8).unwrap(), blake3::hash(b"hello wo"));
+        assert_eq!(hash(0).unwrap(), blake3::hash(b""));
+        assert!(hash(12).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn join_ip_port_brackets_ipv6() {
+        assert_eq!(join_ip_port("1.2.3.4", 5), "1.2.3.4:5");
+        assert_eq!(join_ip_port("::1", 5), "[::1]:5");
+        assert_eq!(join_ip_port("fe80::1%eth0", 5), "[fe80::1%eth0]:5");
+        assert_eq!(join_ip_port("fe80::1%2", 5), "[fe80::1%2]:5");
+        assert_eq!(join_ip_port("[::1]", 5), "[::1]:5");
+    }
+
+    #[test]
+    fn resolve_addr_reads_ips_and_scope_ids() {
+        let resolve = |addr| resolve_addr(addr).unwrap();
+        assert_eq!(resolve("1.2.3.4:5"), ["1.2.3.4:5".parse().unwrap()]);
+        assert_eq!(resolve("[::1]:5"), ["[::1]:5".parse().unwrap()]);
+        let SocketAddr::V6(v6) = resolve("[fe80::1%2]:5")[0] else {
+            panic!("not an IPv6 address");
+        };
+        assert_eq!((v6.ip().to_string(), v6.port(), v6.scope_id()), ("fe80::1".into(), 5, 2));
+        //interface names become their index
+        let iface = datalink::interfaces().into_iter().next().unwrap();
+        let SocketAddr::V6(v6) = resolve(&format!("[fe80::1%{}]:5", iface.name))[0] else {
+            panic!("not an IPv6 address");
+        };
+        assert_eq!(v6.scope_id(), iface.index);
+        assert!(resolve_addr("[fe80::1%no_such_iface]:5").is_err());
+    }
+
+    #[test]
+    fn offer_new_checks_name_and_size() {
+        let entry = |path: String, dir| TreeEntry { path, size: 0, modified: 0, dir };
+        let root = || vec![entry(String::new(), true)];
+        assert_eq!(Offer::new(1, Path::new("/tmp/dir"), root()).unwrap().name, "dir");
+        assert!(Offer::new(1, Path::new("/"), root()).is_err());
+        let mut many = root();
+        many.extend((0..20_000).map(|i| entry(format!("file_{i:05}"), false)));
+        assert!(Offer::new(1, Path::new("many"), many.clone()).is_ok());
+        let files = MAX_OFFER_SIZE as usize / 20;
+        many.extend((0..files).map(|i| entry(format!("more_{i}"), false)));
+        assert!(Offer::new(1, Path::new("many"), many).is_err());
+    }
+
+    #[test]
+    fn skip_frame_keeps_counter_in_step() {
+        let mut send = CipherState::new([7; 32], None);
+        let mut recv = CipherState::new([7; 32], None);
+        let mut stream = vec![];
+        write_frame(&mut stream, &mut send, b"from the future", 200).unwrap();
+        write_frame(&mut stream, &mut send, b"hi", MessageType::Text as u8).unwrap();
+        let mut reader = &stream[..];
+        let (mut header, mut buf) = ([0; 8], vec![]);
+        reader.read_exact(&mut header).unwrap();
+        assert!(MessageType::try_from(header[0]).is_err());
+        skip_frame(&mut reader, &mut buf, &header, &mut recv, 1024).unwrap();
+        reader.read_exact(&mut header).unwrap();
+        read_frame(&mut reader, &mut buf, &header, 1024).unwrap();
+        assert_eq!(recv.decrypt(&buf, MessageType::Text as u8).unwrap(), b"hi");
+    }
+}
@@ -0,0 +1,162 @@
+use crate::app::AppEvent::{self, *};
+use crate::connections::{put_str, FieldReader, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+///UDP port beacons are sent to and received on
+const DISCOVERY_PORT: u16 = 47474;
+///Administratively scoped multicast group beacons are sent to, not routed past the local network
+const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 74, 74);
+///How often each instance sends its [`Beacon`]
+const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+///Time without a beacon after which an instance is no longer listed
+pub(crate) const BEACON_EXPIRY: Duration = Duration::from_secs(16);
+///Start of every beacon, to ignore other traffic on the port
+const BEACON_MAGIC: &[u8; 4] = b"tmrs";
+///How often the service checks if the app is still running while no beacons arrive
+const DISCOVERY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+///Announces an instance on the local network, sent by every instance with discovery enabled
+///
+///Anyone on the network can send one, so the fingerprint is only
+///trusted once the handshake after connecting proves it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Beacon {
+    pub(crate) nick: Option<String>,
+    ///Fingerprint of the instance's identity key, see [`crate::identity::fingerprint`]
+    pub(crate) fingerprint: String,
+    ///Addresses the instance is listening on
+    pub(crate) listen_addrs: Vec<String>
+}
+
+impl Beacon {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BEACON_MAGIC.to_vec();
+        bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        put_str(&mut bytes, &self.fingerprint);
+        put_str(&mut bytes, self.nick.as_deref().unwrap_or_default());
+        bytes.push(self.listen_addrs.len().min(u8::MAX as usize) as u8);
+        for addr in self.listen_addrs.iter().take(u8::MAX as usize) {
+            put_str(&mut bytes, addr);
+        }
+        bytes
+    }
+
+    ///Parses a beacon, returns an error for anything else or one from an incompatible client
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = FieldReader(bytes);
+        if reader.array::<4>()? != *BEACON_MAGIC || reader.u16()? < MIN_PROTOCOL_VERSION {
+            return Err(eyre!("not a compatible beacon"));
+        }
+        let fingerprint = reader.str()?;
+        let nick = Some(reader.str()?).filter(|n| !n.is_empty());
+        let listen_addrs = (0..reader.array::<1>()?[0])
+            .map(|_| reader.str())
+            .collect::<Result<_>>()?;
+        Ok(Self { nick, fingerprint, listen_addrs })
+    }
+}
+
+///An instance found on the local network, listed by `/discover`
+#[derive(Debug, Clone)]
+pub(crate) struct DiscoveredPeer {
+    pub(crate) nick: Option<String>,
+    pub(crate) fingerprint: String,
+    ///Address to connect to, see [`connect_addr`]
+    pub(crate) addr: String,
+    ///When its last beacon arrived
+    pub(crate) last_seen: Instant
+}
+
+///Sends `beacon` to the local network every [`BEACON_INTERVAL`]
+///and sends beacons from other instances to the app as [`DiscoveryEvent`]s
+pub(crate) fn discovery_service(
+    tx: Sender<AppEvent>,
+    running: Arc<AtomicBool>,
+    beacon: Arc<RwLock<Beacon>>
+) -> Result<()> {
+    let socket = match bind_discovery_socket() {
+        Ok(s) => s,
+        Err(e) => {
+            tx.send(ErrorEvent(format!("Failed to start discovery: {e}")))?;
+            return Ok(());
+        }
+    };
+    let group = SocketAddr::from((DISCOVERY_GROUP, DISCOVERY_PORT));
+    let mut last_sent: Option<Instant> = None;
+    let mut buf = [0u8; 4096];
+    while running.load(Ordering::Relaxed) {
+        if last_sent.is_none_or(|s| s.elapsed() >= BEACON_INTERVAL) {
+            let beacon = beacon.read().unwrap().clone();
+            //nothing to announce until a listener is up
+            if !beacon.listen_addrs.is_empty() {
+                //the network may be down for a while, keep listening and try again later
+                let _ = socket.send_to(&beacon.to_bytes(), group);
+            }
+            last_sent = Some(Instant::now());
+        }
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tx.send(ErrorEvent(format!("Stopped discovery: {e}")))?;
+                return Ok(());
+            }
+        };
+        let Ok(received) = Beacon::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        if received.fingerprint == beacon.read().unwrap().fingerprint {
+            continue;
+        }
+        let Some(addr) = connect_addr(&received.listen_addrs, from.ip()) else {
+            continue;
+        };
+        tx.send(DiscoveryEvent(DiscoveredPeer {
+            nick: received.nick,
+            fingerprint: received.fingerprint,
+            addr,
+            last_seen: Instant::now()
+        }))?;
+    }
+
+    Ok(())
+}
+
+///Binds a UDP socket to [`DISCOVERY_PORT`] that receives beacons sent to [`DISCOVERY_GROUP`],
+///shared with any other instances on the same machine
+fn bind_discovery_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).into())?;
+    socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    //so instances on the same machine find each other
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(DISCOVERY_POLL_INTERVAL))?;
+
+    Ok(socket.into())
+}
+
+///Picks the address to connect to from the `listen_addrs` of a beacon sent from `source`,
+///preferring one on the IP the beacon came from, then any that isn't a loopback address
+///
+///Unspecified IPs like `0.0.0.0` are replaced with `source`
+fn connect_addr(listen_addrs: &[String], source: IpAddr) -> Option<String> {
+    let addrs: Vec<SocketAddr> = listen_addrs.iter()
+        .filter_map(|a| a.parse::<SocketAddr>().ok())
+        .map(|a| if a.ip().is_unspecified() { SocketAddr::new(source, a.port()) } else { a })
+        .collect();
+    addrs.iter().find(|a| a.ip() == source)
+        .or_else(|| addrs.iter().find(|a| !a.ip().is_loopback()))
+        .or(addrs.first())
+        .map(|a| a.to_string())
+}
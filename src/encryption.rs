@@ -1,40 +1,321 @@
-use chacha20poly1305::aead::common::Generate;
-use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::{Aead, Payload};
 use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::time::{Duration, Instant};
 use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroize;
 
-pub(crate) fn encrypt(bytes: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
-    let cipher = ChaCha20Poly1305::new(&Key::try_from(*key)?);
-    let nonce = Nonce::generate();
-    let encrypted = cipher.encrypt(&nonce, bytes)?;
-    let mut output = Vec::from(nonce.0);
-    output.extend(encrypted);
+///Bytes added to each message by [`CipherState::encrypt`],
+///8 for the message counter and 16 for the tag
+pub(crate) const ENCRYPTION_OVERHEAD: u64 = 24;
+///Context for deriving the directional keys from the shared secret
+const KEY_INFO: &[u8] = b"tcp_messenger directional keys";
+///Context for deriving the next key from the current one in [`CipherState::ratchet`]
+const RATCHET_INFO: &[u8] = b"tcp_messenger rekey";
 
-    Ok(output)
+///Limits on how much a key is used before it's replaced, 0 disables a limit
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RekeyLimits {
+    pub(crate) messages: u64,
+    pub(crate) bytes: u64,
+    pub(crate) interval: Duration
 }
 
-pub(crate) fn decrypt(bytes: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
-    let cipher = ChaCha20Poly1305::new(&Key::try_from(*key)?);
-    //get the first 12 bytes as a sized array, needed for conversion
-    let n = [
-        bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5],
-        bytes[6], bytes[7], bytes[8], bytes[9], bytes[10], bytes[11]
-    ];
-    let nonce = Nonce::from(n);
+///Key and message counter for one direction of a connection
+///
+///The counter is used as the nonce, so every message is encrypted with a fresh nonce
+///and the receiving side can reject any message that's replayed or out of order
+pub(crate) struct CipherState {
+    key: [u8; 32],
+    ///Cipher for `key`, kept so the key isn't copied for every message, wipes its key on drop
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    ///Only set for the sending side, which decides when to rekey
+    limits: Option<RekeyLimits>,
+    ///Messages encrypted with the current key
+    messages: u64,
+    ///Bytes encrypted with the current key
+    bytes: u64,
+    ///When the current key was derived
+    since: Instant
+}
+
+impl CipherState {
+    pub(crate) fn new(key: [u8; 32], limits: Option<RekeyLimits>) -> Self {
+        Self {
+            key,
+            cipher: new_cipher(&key),
+            counter: 0,
+            limits,
+            messages: 0,
+            bytes: 0,
+            since: Instant::now()
+        }
+    }
+
+    ///Returns whether the current key has reached one of the [`RekeyLimits`]
+    pub(crate) fn needs_rekey(&self) -> bool {
+        self.limits.is_some_and(|l| {
+            (l.messages > 0 && self.messages >= l.messages)
+                || (l.bytes > 0 && self.bytes >= l.bytes)
+                || (!l.interval.is_zero() && self.since.elapsed() >= l.interval)
+        })
+    }
+
+    ///Replaces the key with one derived from it and wipes the old one,
+    ///so the new key can't be used to recover the old one or decrypt earlier messages
+    pub(crate) fn ratchet(&mut self) -> Result<()> {
+        let mut next = [0u8; 32];
+        Hkdf::<Sha256>::from_prk(&self.key)
+            .map_err(|_| eyre!("Failed to derive the next key"))?
+            .expand(RATCHET_INFO, &mut next)
+            .map_err(|_| eyre!("Failed to derive the next key"))?;
+        self.key.zeroize();
+        self.key = next;
+        next.zeroize();
+        self.cipher = new_cipher(&self.key);
+        self.messages = 0;
+        self.bytes = 0;
+        self.since = Instant::now();
+
+        Ok(())
+    }
+
+    ///Encrypts `bytes` with the next counter value, authenticating `msg_type` along with them
+    pub(crate) fn encrypt(&mut self, bytes: &[u8], msg_type: u8) -> Result<Vec<u8>> {
+        let encrypted = self.cipher.encrypt(
+            &nonce(self.counter),
+            Payload { msg: bytes, aad: &[msg_type] }
+        )?;
+        let mut output = self.counter.to_be_bytes().to_vec();
+        output.extend(encrypted);
+        self.counter += 1;
+        self.messages += 1;
+        self.bytes += bytes.len() as u64;
+
+        Ok(output)
+    }
+
+    ///Decrypts a message from [`CipherState::encrypt`],
+    ///returns an error if it isn't the next message expected
+    pub(crate) fn decrypt(&mut self, bytes: &[u8], msg_type: u8) -> Result<Vec<u8>> {
+        if bytes.len() < ENCRYPTION_OVERHEAD as usize {
+            return Err(eyre!("sent a truncated message"));
+        }
+        let counter = u64::from_be_bytes(bytes[..8].try_into()?);
+        if counter != self.counter {
+            return Err(eyre!(
+                "sent message {counter} when {} was expected, possibly replayed", self.counter
+            ));
+        }
+        let decrypted = self.cipher.decrypt(
+            &nonce(counter),
+            Payload { msg: &bytes[8..], aad: &[msg_type] }
+        ).map_err(|_| eyre!("sent a message that failed to decrypt"))?;
+        self.counter += 1;
+
+        Ok(decrypted)
+    }
+}
+
+impl Drop for CipherState {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl Debug for CipherState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CipherState").field("counter", &self.counter).finish_non_exhaustive()
+    }
+}
+
+///Creates a cipher for `key`, wiping the copy of the key made for it
+fn new_cipher(key: &[u8; 32]) -> ChaCha20Poly1305 {
+    let mut key = Key::from(*key);
+    let cipher = ChaCha20Poly1305::new(&key);
+    key.as_mut_slice().zeroize();
+
+    cipher
+}
+
+///Returns the 96-bit nonce for `counter`
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+///Derives separate keys for each direction from the shared secret of `handshake`,
+///returns the [`CipherState`]s for sending and receiving
+pub(crate) fn derive_keys(
+    handshake: &Handshake,
+    limits: RekeyLimits
+) -> Result<(CipherState, CipherState)> {
+    if handshake.local_key == handshake.peer_key {
+        return Err(eyre!("Peer sent back our own public key"));
+    }
+    //order the public keys the same way on both sides to agree on which key is which
+    let (low, high) = if handshake.local_key < handshake.peer_key {
+        (&handshake.local_key, &handshake.peer_key)
+    } else {
+        (&handshake.peer_key, &handshake.local_key)
+    };
+    let mut info = KEY_INFO.to_vec();
+    info.extend_from_slice(low);
+    info.extend_from_slice(high);
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(None, &handshake.secret)
+        .expand(&info, &mut okm)
+        .map_err(|_| eyre!("Failed to derive keys"))?;
+    //the first key is for messages from the peer with the lower public key
+    let mut send: [u8; 32] = okm[..32].try_into()?;
+    let mut recv: [u8; 32] = okm[32..].try_into()?;
+    okm.zeroize();
+    if handshake.local_key > handshake.peer_key {
+        std::mem::swap(&mut send, &mut recv);
+    }
+    let keys = (CipherState::new(send, Some(limits)), CipherState::new(recv, None));
+    send.zeroize();
+    recv.zeroize();
 
-    Ok(cipher.decrypt(&nonce, &bytes[12..])?)
+    Ok(keys)
 }
 
-pub(crate) fn establish_shared_secret(stream: &mut TcpStream) -> Result<[u8; 32]> {
+///Shared secret and the ephemeral public keys it was derived from,
+///returned by [`establish_shared_secret`]
+#[derive(Debug)]
+pub(crate) struct Handshake {
+    pub(crate) secret: [u8; 32],
+    pub(crate) local_key: [u8; 32],
+    pub(crate) peer_key: [u8; 32]
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+///Exchanges ephemeral public keys over `stream` and derives the shared secret,
+///mixing in `psk` if set so the secret only matches with peers that use the same passphrase
+pub(crate) fn establish_shared_secret(
+    stream: &mut TcpStream,
+    psk: Option<&str>
+) -> Result<Handshake> {
     let mut buf = [0u8; 32];
     let es = EphemeralSecret::random(); //random_from_rng(OsRng);
     let pk = PublicKey::from(&es);
     stream.write_all(&pk.to_bytes())?;
     stream.flush()?;
     stream.read_exact(&mut buf)?;
+    let mut secret = es.diffie_hellman(&PublicKey::from(buf)).to_bytes();
+    if let Some(psk) = psk.filter(|p| !p.is_empty()) {
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(psk.as_bytes()), &secret);
+        secret.zeroize();
+        secret = prk.into();
+    }
+
+    Ok(Handshake {
+        secret,
+        local_key: pk.to_bytes(),
+        peer_key: buf
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: u8 = 255;
+
+    fn pair() -> (CipherState, CipherState) {
+        (CipherState::new([7; 32], None), CipherState::new([7; 32], None))
+    }
+
+    #[test]
+    fn round_trips_in_order() {
+        let (mut send, mut recv) = pair();
+        for msg in [&b"first"[..], b"second", b""] {
+            let encrypted = send.encrypt(msg, TEXT).unwrap();
+            assert_eq!(encrypted.len(), msg.len() + ENCRYPTION_OVERHEAD as usize);
+            assert_eq!(recv.decrypt(&encrypted, TEXT).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_frame() {
+        let (mut send, mut recv) = pair();
+        let encrypted = send.encrypt(b"hello", TEXT).unwrap();
+        recv.decrypt(&encrypted, TEXT).unwrap();
+        assert!(recv.decrypt(&encrypted, TEXT).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_frame() {
+        let (mut send, mut recv) = pair();
+        let first = send.encrypt(b"first", TEXT).unwrap();
+        let second = send.encrypt(b"second", TEXT).unwrap();
+        assert!(recv.decrypt(&second, TEXT).is_err());
+        //a rejected frame doesn't move the counter
+        assert_eq!(recv.decrypt(&first, TEXT).unwrap(), b"first");
+        assert_eq!(recv.decrypt(&second, TEXT).unwrap(), b"second");
+    }
+
+    #[test]
+    fn rejects_different_msg_type() {
+        let (mut send, mut recv) = pair();
+        let encrypted = send.encrypt(b"/nick mallory", TEXT).unwrap();
+        assert!(recv.decrypt(&encrypted, TEXT - 3).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_or_truncated_frame() {
+        let (mut send, mut recv) = pair();
+        let mut encrypted = send.encrypt(b"hello", TEXT).unwrap();
+        assert!(recv.decrypt(&encrypted[..ENCRYPTION_OVERHEAD as usize - 1], TEXT).is_err());
+        *encrypted.last_mut().unwrap() ^= 1;
+        assert!(recv.decrypt(&encrypted, TEXT).is_err());
+    }
+
+    #[test]
+    fn rejects_frame_after_one_sided_ratchet() {
+        let (mut send, mut recv) = pair();
+        send.ratchet().unwrap();
+        let encrypted = send.encrypt(b"hello", TEXT).unwrap();
+        assert!(recv.decrypt(&encrypted, TEXT).is_err());
+    }
+
+    #[test]
+    fn round_trips_after_ratchet_on_both_sides() {
+        let (mut send, mut recv) = pair();
+        let before = send.encrypt(b"before", TEXT).unwrap();
+        assert_eq!(recv.decrypt(&before, TEXT).unwrap(), b"before");
+        send.ratchet().unwrap();
+        recv.ratchet().unwrap();
+        let after = send.encrypt(b"after", TEXT).unwrap();
+        assert_eq!(recv.decrypt(&after, TEXT).unwrap(), b"after");
+        //the old key can't decrypt anything new
+        let (_, mut old) = pair();
+        assert!(old.decrypt(&before, TEXT).is_ok());
+        assert!(old.decrypt(&after, TEXT).is_err());
+    }
 
-    Ok(es.diffie_hellman(&PublicKey::from(buf)).to_bytes())
+    #[test]
+    fn needs_rekey_after_message_limit() {
+        let limits = RekeyLimits { messages: 2, bytes: 0, interval: Duration::ZERO };
+        let mut send = CipherState::new([7; 32], Some(limits));
+        send.encrypt(b"one", TEXT).unwrap();
+        assert!(!send.needs_rekey());
+        send.encrypt(b"two", TEXT).unwrap();
+        assert!(send.needs_rekey());
+        send.ratchet().unwrap();
+        assert!(!send.needs_rekey());
+    }
 }
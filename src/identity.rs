@@ -0,0 +1,238 @@
+use crate::encryption::Handshake;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+///Prefix of the data signed during the handshake, so the signature can't be reused elsewhere
+const HANDSHAKE_CONTEXT: &[u8] = b"tcp_messenger handshake";
+///Prefix of the data hashed for [`short_auth_string`]
+const SAS_CONTEXT: &[u8] = b"tcp_messenger short authentication string";
+///Emoji used for [`short_auth_string`], each with a name for reading them aloud
+const SAS_EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"), ("🐱", "Cat"), ("🦁", "Lion"), ("🐎", "Horse"),
+    ("🦄", "Unicorn"), ("🐷", "Pig"), ("🐘", "Elephant"), ("🐰", "Rabbit"),
+    ("🐼", "Panda"), ("🐓", "Rooster"), ("🐧", "Penguin"), ("🐢", "Turtle"),
+    ("🐟", "Fish"), ("🐙", "Octopus"), ("🦋", "Butterfly"), ("🌷", "Flower"),
+    ("🌳", "Tree"), ("🌵", "Cactus"), ("🍄", "Mushroom"), ("🌏", "Globe"),
+    ("🌙", "Moon"), ("☁️", "Cloud"), ("🔥", "Fire"), ("🍌", "Banana"),
+    ("🍎", "Apple"), ("🍓", "Strawberry"), ("🌽", "Corn"), ("🍕", "Pizza"),
+    ("🎂", "Cake"), ("❤️", "Heart"), ("😀", "Smiley"), ("🤖", "Robot"),
+    ("🎩", "Hat"), ("👓", "Glasses"), ("🔧", "Spanner"), ("🎅", "Santa"),
+    ("👍", "Thumbs Up"), ("☂️", "Umbrella"), ("⌛", "Hourglass"), ("⏰", "Clock"),
+    ("🎁", "Gift"), ("💡", "Light Bulb"), ("📕", "Book"), ("✏️", "Pencil"),
+    ("📎", "Paperclip"), ("✂️", "Scissors"), ("🔒", "Lock"), ("🔑", "Key"),
+    ("🔨", "Hammer"), ("☎️", "Telephone"), ("🏁", "Flag"), ("🚂", "Train"),
+    ("🚲", "Bicycle"), ("✈️", "Aeroplane"), ("🚀", "Rocket"), ("🏆", "Trophy"),
+    ("⚽", "Ball"), ("🎸", "Guitar"), ("🎺", "Trumpet"), ("🔔", "Bell"),
+    ("⚓", "Anchor"), ("🎧", "Headphones"), ("📁", "Folder"), ("📌", "Pin"),
+];
+
+///Long-term identity key, used to sign the ephemeral keys of each handshake
+pub(crate) struct Identity {
+    key: SigningKey
+}
+
+impl Identity {
+    ///Loads the identity key from `path`, or generates a new one and saves it there
+    pub(crate) fn load_or_generate(path: &Path) -> Result<Self> {
+        if let Ok(bytes) = fs::read(path) {
+            let seed: [u8; 32] = bytes.try_into().map_err(|_| eyre!(
+                "Identity key {} is invalid, move it to generate a new one", path.display()
+            ))?;
+            return Ok(Self { key: SigningKey::from_bytes(&seed) });
+        }
+
+        let mut seed = [0u8; 32];
+        getrandom::fill(&mut seed).map_err(|e| eyre!("Failed to generate identity key: {e}"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(target_family = "unix")]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(&seed)?;
+
+        Ok(Self { key: SigningKey::from_bytes(&seed) })
+    }
+
+    pub(crate) fn public_key(&self) -> [u8; 32] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    ///Signs both ephemeral keys of `handshake`, ours first
+    pub(crate) fn sign_handshake(&self, handshake: &Handshake) -> [u8; 64] {
+        let transcript = transcript(&handshake.local_key, &handshake.peer_key);
+        self.key.sign(&transcript).to_bytes()
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Identity({})", short_fingerprint(&fingerprint(&self.public_key())))
+    }
+}
+
+///Checks that `signature` is the peer's signature of `handshake` made with `identity_key`
+pub(crate) fn verify_handshake(
+    identity_key: &[u8; 32],
+    signature: &[u8; 64],
+    handshake: &Handshake
+) -> Result<()> {
+    let key = VerifyingKey::from_bytes(identity_key)
+        .map_err(|_| eyre!("sent an invalid identity key"))?;
+    key.verify(
+        &transcript(&handshake.peer_key, &handshake.local_key),
+        &Signature::from_bytes(signature)
+    ).map_err(|_| eyre!("failed to prove its identity"))
+}
+
+///Data signed by the peer owning `signer_key`
+fn transcript(signer_key: &[u8; 32], other_key: &[u8; 32]) -> Vec<u8> {
+    let mut transcript = HANDSHAKE_CONTEXT.to_vec();
+    transcript.extend_from_slice(signer_key);
+    transcript.extend_from_slice(other_key);
+    transcript
+}
+
+///Returns 7 emoji with their names derived from both ephemeral and identity keys of a handshake,
+///both peers get the same ones unless someone is intercepting the connection
+pub(crate) fn short_auth_string(
+    handshake: &Handshake,
+    local_identity: &[u8; 32],
+    peer_identity: &[u8; 32]
+) -> Vec<(&'static str, &'static str)> {
+    //order the keys the same way on both sides
+    let mut keys = [
+        (handshake.local_key, local_identity),
+        (handshake.peer_key, peer_identity),
+    ];
+    keys.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(SAS_CONTEXT);
+    for (ephemeral_key, identity_key) in keys {
+        hasher.update(ephemeral_key);
+        hasher.update(identity_key);
+    }
+    let hash = hasher.finalize();
+    //first 42 bits of the hash, 6 bits per emoji
+    let bits = u64::from_be_bytes(hash[..8].try_into().unwrap());
+
+    (0..7).map(|i| SAS_EMOJI[(bits >> (58 - i * 6)) as usize & 63]).collect()
+}
+
+///Returns the SHA-256 hash of an identity key as lowercase hex
+pub(crate) fn fingerprint(identity_key: &[u8; 32]) -> String {
+    Sha256::digest(identity_key).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+///Shortens a [`fingerprint`] to its first 16 bytes, in groups of 4 hex digits for reading
+pub(crate) fn short_fingerprint(fingerprint: &str) -> String {
+    fingerprint.as_bytes()
+        .chunks(4)
+        .take(8)
+        .map(|c| String::from_utf8_lossy(c).to_string())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+///Result of [`KnownPeers::check`]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TrustCheck {
+    ///First time seeing this peer, its fingerprint was recorded
+    New,
+    ///Fingerprint matches the recorded one
+    Known,
+    ///Fingerprint doesn't match, contains the recorded one
+    Changed(String)
+}
+
+///A peer in [`KnownPeers`]
+#[derive(Debug)]
+struct KnownPeer {
+    host: String,
+    fingerprint: String,
+    ///Whether the fingerprint was marked as checked out-of-band with `/trust`,
+    ///after comparing the short authentication string `/verify` shows
+    verified: bool
+}
+
+///Fingerprints of peers seen before, stored like SSH's `known_hosts`
+///as one `HOST FINGERPRINT [verified]` entry per line
+#[derive(Debug)]
+pub(crate) struct KnownPeers {
+    path: PathBuf,
+    peers: Vec<KnownPeer>
+}
+
+impl KnownPeers {
+    ///Loads known peers from `path`, a missing file is treated as empty
+    pub(crate) fn load(path: &Path) -> Self {
+        let peers = fs::read_to_string(path).unwrap_or_default()
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('#'))
+            .filter_map(|l| {
+                let mut parts = l.split_whitespace();
+                Some(KnownPeer {
+                    host: parts.next()?.to_string(),
+                    fingerprint: parts.next()?.to_string(),
+                    verified: parts.next() == Some("verified")
+                })
+            })
+            .collect();
+
+        Self { path: path.to_path_buf(), peers }
+    }
+
+    ///Compares `fingerprint` to the one recorded for `host`, recording it if there is none
+    pub(crate) fn check(&mut self, host: &str, fingerprint: &str) -> Result<TrustCheck> {
+        match self.peers.iter().find(|p| p.host == host) {
+            Some(p) if p.fingerprint == fingerprint => Ok(TrustCheck::Known),
+            Some(p) => Ok(TrustCheck::Changed(p.fingerprint.clone())),
+            None => {
+                self.peers.push(KnownPeer {
+                    host: host.to_string(),
+                    fingerprint: fingerprint.to_string(),
+                    verified: false
+                });
+                self.save()?;
+                Ok(TrustCheck::New)
+            }
+        }
+    }
+
+    ///Records `fingerprint` as the verified fingerprint of `host`, replacing any other
+    pub(crate) fn trust(&mut self, host: &str, fingerprint: &str) -> Result<()> {
+        self.peers.retain(|p| p.host != host);
+        self.peers.push(KnownPeer {
+            host: host.to_string(),
+            fingerprint: fingerprint.to_string(),
+            verified: true
+        });
+        self.save()
+    }
+
+    ///Returns whether `fingerprint` is the verified fingerprint of `host`
+    pub(crate) fn is_verified(&self, host: &str, fingerprint: &str) -> bool {
+        self.peers.iter().any(|p| p.host == host && p.fingerprint == fingerprint && p.verified)
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = String::new();
+        for peer in &self.peers {
+            contents.push_str(&format!("{} {}", peer.host, peer.fingerprint));
+            contents.push_str(if peer.verified { " verified\n" } else { "\n" });
+        }
+        fs::write(&self.path, contents)?;
+
+        Ok(())
+    }
+}
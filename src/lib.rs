@@ -0,0 +1,14 @@
+mod app;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod config;
+mod connections;
+mod discovery;
+mod encryption;
+mod identity;
+mod ratelimit;
+mod scheduler;
+
+pub use crate::app::App;
+pub use crate::config::Config;
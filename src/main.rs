@@ -1,11 +1,5 @@
-mod app;
-mod config;
-mod connections;
-mod encryption;
-
-use crate::app::App;
-use crate::config::Config;
 use color_eyre::Result;
+use tcp_messenger_rs::{App, Config};
 
 fn main() -> Result<()> {
     color_eyre::install()?;
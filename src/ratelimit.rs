@@ -0,0 +1,105 @@
+use size::Size;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+///Token bucket limiting how fast data is transferred,
+///shared by every thread transferring data it applies to
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    ///Bytes per second, 0 for unlimited
+    rate: AtomicU64,
+    ///Bytes that can be transferred right away, negative if transfers are ahead of the rate,
+    ///and when that was last worked out
+    bucket: Mutex<(f64, Instant)>
+}
+
+impl RateLimiter {
+    ///Creates a limiter for `rate` bytes per second, or an unlimited one if it's [`None`]
+    pub(crate) fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: AtomicU64::new(rate.unwrap_or(0)),
+            bucket: Mutex::new((0.0, Instant::now()))
+        }
+    }
+
+    pub(crate) fn rate(&self) -> Option<u64> {
+        Some(self.rate.load(Ordering::Relaxed)).filter(|r| *r > 0)
+    }
+
+    ///Changes the rate, transfers already waiting keep waiting for the old one
+    pub(crate) fn set_rate(&self, rate: Option<u64>) {
+        *self.bucket.lock().unwrap() = (0.0, Instant::now());
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    ///Blocks until `bytes` more can be transferred without going over the rate
+    pub(crate) fn throttle(&self, bytes: u64) {
+        let rate = self.rate.load(Ordering::Relaxed) as f64;
+        if rate == 0.0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (available, last) = &mut *bucket;
+            let now = Instant::now();
+            //save up at most a second's worth while idle, so bursts stay short
+            *available = (*available + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *available -= bytes as f64;
+            Duration::from_secs_f64((-*available).max(0.0) / rate)
+        };
+        sleep(wait);
+    }
+
+    ///Returns the rate for showing to the user
+    pub(crate) fn display(&self) -> String {
+        match self.rate() {
+            Some(r) => format!("{}/s", Size::from_bytes(r)),
+            None => "unlimited".to_string()
+        }
+    }
+}
+
+///Upload and download [`RateLimiter`]s for the same peers
+#[derive(Debug)]
+pub(crate) struct RateLimits {
+    pub(crate) upload: RateLimiter,
+    pub(crate) download: RateLimiter
+}
+
+impl RateLimits {
+    pub(crate) fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self { upload: RateLimiter::new(upload), download: RateLimiter::new(download) }
+    }
+}
+
+///Parses a rate like `500KiB`, `2 MB/s` or a number of bytes per second,
+///returns `Some(None)` for `off` or `0`, and [`None`] if it isn't a rate
+pub(crate) fn parse_rate(rate: &str) -> Option<Option<u64>> {
+    let rate = rate.trim();
+    if rate.eq_ignore_ascii_case("off") {
+        return Some(None);
+    }
+    let rate = rate.strip_suffix("/s").unwrap_or(rate).trim_end();
+    let bytes = Size::from_str(rate).ok()?.bytes();
+
+    (bytes >= 0).then_some(Some(bytes as u64).filter(|b| *b > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_accepts_spaces_and_units() {
+        assert_eq!(parse_rate("2 MB/s"), Some(Some(2_000_000)));
+        assert_eq!(parse_rate("500 KiB"), Some(Some(512_000)));
+        assert_eq!(parse_rate("1MiB /s"), Some(Some(1_048_576)));
+        assert_eq!(parse_rate("4096"), Some(Some(4096)));
+        assert_eq!(parse_rate("off"), Some(None));
+        assert_eq!(parse_rate("0"), Some(None));
+        assert_eq!(parse_rate("fast"), None);
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+///Decides whose turn it is to write a frame to a connection,
+///so chat and control messages don't queue up behind file transfers
+///
+///Control frames go first, chunks of file pieces wait until none are waiting
+///and take turns between transfers, one chunk at a time,
+///with only a few pieces being sent at once so the peer doesn't have to buffer more
+#[derive(Debug, Default)]
+pub(crate) struct Scheduler {
+    state: Mutex<SchedulerState>,
+    turn: Condvar
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    ///Control frames waiting to be or being written
+    control: usize,
+    ///Streams with a chunk waiting to be written, in the order they get their turn
+    streams: VecDeque<u64>,
+    ///Whether a chunk is being written
+    busy: bool,
+    ///Pieces with chunks being written
+    pieces: usize
+}
+
+impl Scheduler {
+    ///Runs `send` to write a control frame as soon as the chunk being written is done
+    pub(crate) fn control<T>(&self, send: impl FnOnce() -> T) -> T {
+        self.state.lock().unwrap().control += 1;
+        let result = send();
+        self.state.lock().unwrap().control -= 1;
+        self.turn.notify_all();
+
+        result
+    }
+
+    ///Waits until fewer than `max` pieces are being sent, then runs `send` to send one
+    pub(crate) fn piece<T>(&self, max: usize, send: impl FnOnce() -> T) -> T {
+        let state = self.state.lock().unwrap();
+        let mut state = self.turn.wait_while(state, |s| s.pieces >= max).unwrap();
+        state.pieces += 1;
+        drop(state);
+
+        let result = send();
+        self.state.lock().unwrap().pieces -= 1;
+        self.turn.notify_all();
+
+        result
+    }
+
+    ///Waits until it's the turn of `stream`, then runs `send` to write one of its chunks
+    pub(crate) fn chunk<T>(&self, stream: u64, send: impl FnOnce() -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        state.streams.push_back(stream);
+        state = self.turn.wait_while(state, |s| {
+            s.busy || s.control > 0 || s.streams.front() != Some(&stream)
+        }).unwrap();
+        state.streams.pop_front();
+        state.busy = true;
+        drop(state);
+
+        let result = send();
+        self.state.lock().unwrap().busy = false;
+        self.turn.notify_all();
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{scope, sleep};
+    use std::time::Duration;
+
+    #[test]
+    fn piece_limits_pieces_sent_at_once() {
+        let scheduler = Scheduler::default();
+        let (sending, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| scheduler.piece(3, || {
+                    most.fetch_max(sending.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    sleep(Duration::from_millis(20));
+                    sending.fetch_sub(1, Ordering::SeqCst);
+                }));
+            }
+        });
+        assert!(most.load(Ordering::SeqCst) <= 3);
+    }
+}